@@ -0,0 +1,8 @@
+#!/bin/bash
+set -e
+version=$(cat ./proto-definition/proto_version)
+# Released versions only, newest first, so servers can keep accepting them for a while
+if [ "$(head -n 1 ./proto-definition/proto_version_history 2>/dev/null)" != "$version" ]; then
+    { echo "$version"; cat ./proto-definition/proto_version_history 2>/dev/null || true; } > ./proto-definition/proto_version_history.tmp
+    mv ./proto-definition/proto_version_history.tmp ./proto-definition/proto_version_history
+fi
@@ -1,10 +1,25 @@
-use std::process::Stdio;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
 
+use anyhow::Context;
+use serde_json::json;
 use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Child;
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::{debug, error, info};
+use tracing::{info, warn};
 
-use instance_container::spawn_pipe_monitor;
+use instance_container::child_process::{ChildSpec, start_child};
+use instance_container::{copy_recursively, spawn_pipe_monitor};
+
+use crate::launch_profile::LaunchProfile;
+
+/// Time Xvfb is given to accept connections before Chrome is started anyway
+const XVFB_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+/// User and group Chrome runs as
+const CHROME_UID: u32 = 1337;
 
 fn parse_url_from_line(line: &str) -> anyhow::Result<String> {
     Ok(line
@@ -14,51 +29,96 @@ fn parse_url_from_line(line: &str) -> anyhow::Result<String> {
         .to_string())
 }
 
-/// Launches a headless Chrome instance with DevTools debugging enabled
-/// Returns the WebSocket URL for DevTools connection
-pub async fn start_chrome(
-    chrome_binary_path: &str,
-    cancellation_token: CancellationToken,
-) -> anyhow::Result<String> {
-    let mut chrome = tokio::process::Command::new(chrome_binary_path);
-    chrome.stdout(Stdio::piped());
-    chrome.stderr(Stdio::piped());
-    // Ensure Chrome process is terminated when this process exits
-    chrome.kill_on_drop(true);
-
-    // Configure Chrome for headless operation with minimal resource usage
-    // and maximum stability for automation purposes
-    chrome
-        .uid(1337)
-        .gid(1337)
-        .arg("--headless")
-        .arg("--no-sandbox")
-        .arg("--disable-gpu")
-        .arg("--remote-debugging-port=0")
-        .arg("--remote-debugging-address=127.0.0.1")
-        .arg("--disable-background-networking")
-        .arg("--disable-background-timer-throttling")
-        .arg("--disable-backgrounding-occluded-windows")
-        .arg("--disable-breakpad")
-        .arg("--disable-component-extensions-with-background-pages")
-        .arg("--disable-domain-reliability")
-        .arg("--disable-extensions")
-        .arg("--disable-features=TranslateUI")
-        .arg("--disable-hang-monitor")
-        .arg("--disable-ipc-flooding-protection")
-        .arg("--disable-popup-blocking")
-        .arg("--disable-dev-shm-usage")
-        .arg("--disable-sync")
-        .arg("--mute-audio")
-        .arg("--no-first-run")
-        .arg("--disable-prompt-on-repost")
-        .arg("--disable-default-apps")
-        .arg("--use-gl=swiftshader")
-        .arg("--window-size=1280,720")
-        .arg("--verbose")
-        .arg("--log-level=DEBUG");
-    info!("Starting Chrome {:?}", chrome);
-    let mut child = chrome.spawn()?;
+/// Directory of a single Chrome launch holding its profile and downloads, removed once the
+/// launch is over, including when the container shuts down
+pub struct LaunchDir(PathBuf);
+
+impl LaunchDir {
+    /// Creates the directories, the profile is seeded with the content of `seed` if set
+    fn create(seed: Option<&Path>) -> anyhow::Result<Self> {
+        let launch_dir =
+            LaunchDir(std::env::temp_dir().join(format!("chrome-launch-{}", uuid::Uuid::new_v4())));
+        let profile_dir = launch_dir.user_data_dir().join("Default");
+        let downloads_dir = launch_dir.downloads_dir();
+        for dir in [
+            &launch_dir.0,
+            &launch_dir.user_data_dir(),
+            &profile_dir,
+            &downloads_dir,
+        ] {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+            chown_to_chrome(dir)?;
+        }
+        if let Some(seed) = seed {
+            copy_recursively(seed, &profile_dir, Some(CHROME_UID))
+                .with_context(|| format!("Failed to seed the profile from {:?}", seed))?;
+        }
+        // Downloads land in the launch dir without asking, whatever client drives Chrome
+        let preferences = json!({
+            "download": {
+                "default_directory": downloads_dir.display().to_string(),
+                "prompt_for_download": false,
+            }
+        });
+        let preferences_path = profile_dir.join("Preferences");
+        std::fs::write(&preferences_path, preferences.to_string())
+            .context("Failed to write Chrome preferences")?;
+        chown_to_chrome(&preferences_path)?;
+        Ok(launch_dir)
+    }
+
+    pub fn user_data_dir(&self) -> PathBuf {
+        self.0.join("user-data")
+    }
+
+    pub fn downloads_dir(&self) -> PathBuf {
+        self.0.join("downloads")
+    }
+}
+
+impl Drop for LaunchDir {
+    fn drop(&mut self) {
+        match std::fs::remove_dir_all(&self.0) {
+            Ok(()) => info!("Removed launch dir {:?}", self.0),
+            Err(e) => warn!("Failed to remove launch dir {:?}: {:?}", self.0, e),
+        }
+    }
+}
+
+fn chown_to_chrome(path: &Path) -> anyhow::Result<()> {
+    std::os::unix::fs::chown(path, Some(CHROME_UID), Some(CHROME_UID))
+        .with_context(|| format!("Failed to hand {:?} to Chrome", path))
+}
+
+/// A running Chrome, killed when the token it was started with is cancelled
+pub struct ChromeProcess {
+    /// WebSocket URL for DevTools connection
+    pub ws_url: String,
+    /// Profile and downloads of this launch, wiped once dropped
+    pub launch_dir: LaunchDir,
+    /// Resolves with the exit status once Chrome exited on its own, or None once it was killed
+    pub exited: JoinHandle<Option<std::io::Result<ExitStatus>>>,
+    /// Cancelled once Chrome is gone, whether it exited or was killed
+    pub gone: CancellationToken,
+}
+
+/// Starts the X server a headful Chrome draws on, returns once it accepts connections
+async fn start_xvfb(display: u32, (width, height): (u32, u32)) -> anyhow::Result<Child> {
+    let mut xvfb = tokio::process::Command::new("Xvfb");
+    xvfb.arg(format!(":{}", display))
+        .arg("-screen")
+        .arg("0")
+        .arg(format!("{}x{}x24", width, height))
+        .arg("-nolisten")
+        .arg("tcp")
+        // Xvfb writes the display number once it is ready
+        .arg("-displayfd")
+        .arg("1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    info!("Starting Xvfb {:?}", xvfb);
+    let mut child = xvfb.spawn().context("Failed to start Xvfb")?;
     let stdout = child
         .stdout
         .take()
@@ -67,45 +127,132 @@ pub async fn start_chrome(
         .stderr
         .take()
         .ok_or_else(|| anyhow::anyhow!("Failed to get stderr"))?;
+    spawn_pipe_monitor(stderr, "Xvfb stderr");
+    let mut stdout_reader = BufReader::new(stdout).lines();
+    match tokio::time::timeout(XVFB_STARTUP_TIMEOUT, stdout_reader.next_line()).await {
+        Ok(Ok(Some(_))) => {}
+        Ok(_) => anyhow::bail!("Xvfb exited on startup"),
+        Err(_) => warn!("Xvfb did not report ready in time, starting Chrome anyway"),
+    }
+    Ok(child)
+}
 
-    tokio::spawn(async move {
-        tokio::select! {
-            _ = cancellation_token.cancelled() => {
-                let res = child.kill().await;
-                error!("Chrome killed: {res:?}");
-            }
-            res = child.wait() => {
-                error!("Chrome exited: {res:?}");
-                cancellation_token.cancel();
-            }
+/// Launches a Chrome instance with DevTools debugging enabled, as set by the launch profile.
+/// Its profile is fresh, or a copy of `seed` if set
+pub async fn start_chrome(
+    chrome_binary_path: &str,
+    launch_profile: &LaunchProfile,
+    seed: Option<&Path>,
+    stop_token: CancellationToken,
+) -> anyhow::Result<ChromeProcess> {
+    let mut xvfb = if launch_profile.headful {
+        let display = launch_profile.xvfb_display();
+        let xvfb = start_xvfb(display, launch_profile.window_size()?).await?;
+        Some((display, xvfb))
+    } else {
+        None
+    };
+
+    // A fresh profile per launch, so a restarted Chrome keeps nothing of the previous one.
+    // Seeding copies a whole saved profile, off the runtime threads
+    let seed = seed.map(Path::to_path_buf);
+    let launch_dir =
+        tokio::task::spawn_blocking(move || LaunchDir::create(seed.as_deref())).await??;
+
+    let chrome_binary_path = chrome_binary_path.to_string();
+    let display = xvfb.as_ref().map(|(display, _)| *display);
+    let timezone = launch_profile.timezone.clone();
+    let user_data_dir = launch_dir.user_data_dir();
+    let flags = launch_profile.chrome_flags();
+    let command = move || {
+        let mut chrome = tokio::process::Command::new(&chrome_binary_path);
+        // Configure Chrome for headless operation with minimal resource usage
+        // and maximum stability for automation purposes
+        if let Some(display) = display {
+            chrome.env("DISPLAY", format!(":{}", display));
         }
-    });
+        if let Some(timezone) = &timezone {
+            chrome.env("TZ", timezone);
+        }
+        chrome
+            .uid(CHROME_UID)
+            .gid(CHROME_UID)
+            .arg(format!("--user-data-dir={}", user_data_dir.display()))
+            .arg("--no-sandbox")
+            // Cookies are encrypted with a fixed key, so a saved profile can be loaded by any container
+            .arg("--password-store=basic")
+            .arg("--disable-gpu")
+            .arg("--remote-debugging-port=0")
+            .arg("--remote-debugging-address=127.0.0.1")
+            .arg("--disable-background-networking")
+            .arg("--disable-background-timer-throttling")
+            .arg("--disable-backgrounding-occluded-windows")
+            .arg("--disable-breakpad")
+            .arg("--disable-component-extensions-with-background-pages")
+            .arg("--disable-domain-reliability")
+            .arg("--disable-features=TranslateUI")
+            .arg("--disable-hang-monitor")
+            .arg("--disable-ipc-flooding-protection")
+            .arg("--disable-popup-blocking")
+            .arg("--disable-dev-shm-usage")
+            .arg("--disable-sync")
+            .arg("--mute-audio")
+            .arg("--no-first-run")
+            .arg("--disable-prompt-on-repost")
+            .arg("--disable-default-apps")
+            .arg("--use-gl=swiftshader")
+            .arg("--verbose")
+            .arg("--log-level=DEBUG")
+            .args(&flags);
+        chrome
+    };
 
-    // Create a channel to receive the DevTools WebSocket URL from Chrome's stderr
+    // Chrome outputs the DevTools WebSocket URL on stderr asynchronously after startup
     let (send, recv) = tokio::sync::oneshot::channel();
-
-    // Monitor Chrome's stderr output to extract the DevTools WebSocket URL
-    // This is necessary because Chrome outputs this URL asynchronously after startup
-    tokio::spawn(async move {
-        let mut send = Some(send);
-        let mut stderr_reader = BufReader::new(stderr).lines();
-        while let Some(line) = stderr_reader
-            .next_line()
-            .await
-            .map_err(|e| anyhow::anyhow!("Failed to read stderr: {e:?}"))?
+    let send = Mutex::new(Some(send));
+    let on_stderr_line = move |line: &str| {
+        if line.contains("DevTools listening on")
+            && let Some(send) = send.lock().ok().and_then(|mut send| send.take())
         {
-            debug!("Chrome stderr: {line}");
-            if let Some(send) = send.take_if(|_| line.contains("DevTools listening on")) {
-                send.send(line.to_owned())
-                    .map_err(|e| anyhow::anyhow!("Failed to send ws path {e:?}"))?;
-            }
+            let _ = send.send(line.to_owned());
         }
-        Ok::<(), anyhow::Error>(())
-    });
+    };
+
+    // Not restarted here, a crash is handled by restarting the whole browser
+    let child = start_child(
+        ChildSpec {
+            name: "chrome".to_string(),
+            command: Box::new(command),
+            readiness: None,
+            restart: None,
+            on_stderr_line: Some(Box::new(on_stderr_line)),
+        },
+        stop_token,
+    )
+    .await?;
 
-    // Monitor stdout separately to ensure logs are captured but not block the main flow
-    spawn_pipe_monitor(stdout, "Chrome stdout");
+    let gone = CancellationToken::new();
+    let gone_guard = gone.clone().drop_guard();
+    let exited = tokio::spawn(async move {
+        let _gone_guard = gone_guard;
+        let exit = child.exited.await.unwrap_or(None);
+        // Waited for, so a restarted Chrome can take over the display
+        if let Some((_, xvfb)) = &mut xvfb {
+            let res = xvfb.kill().await;
+            info!("Xvfb killed: {res:?}");
+        }
+        exit
+    });
 
-    let url = parse_url_from_line(recv.await?.as_str())?;
-    Ok(url)
+    let ws_url = parse_url_from_line(
+        recv.await
+            .context("Chrome exited before DevTools were listening")?
+            .as_str(),
+    )?;
+    Ok(ChromeProcess {
+        ws_url,
+        launch_dir,
+        exited,
+        gone,
+    })
 }
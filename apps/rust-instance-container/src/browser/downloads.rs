@@ -0,0 +1,129 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Serialize;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+/// Chrome writes a download under this suffix until it is complete
+const PARTIAL_DOWNLOAD_SUFFIX: &str = ".crdownload";
+
+/// Downloads directory of the running Chrome, rebound when Chrome is restarted with a new one
+#[derive(Clone)]
+pub struct DownloadsDir(Arc<RwLock<PathBuf>>);
+
+impl DownloadsDir {
+    pub fn new(dir: &Path) -> Self {
+        Self(Arc::new(RwLock::new(dir.to_path_buf())))
+    }
+
+    pub fn rebind(&self, dir: &Path) {
+        if let Ok(mut current) = self.0.write() {
+            *current = dir.to_path_buf();
+        }
+    }
+
+    fn get(&self) -> Option<PathBuf> {
+        self.0.read().ok().map(|dir| dir.clone())
+    }
+}
+
+#[derive(Serialize)]
+struct Download {
+    name: String,
+    size_bytes: u64,
+}
+
+/// Completed downloads, by name
+async fn read_downloads(dir: &Path) -> std::io::Result<Vec<Download>> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    let mut downloads = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !metadata.is_file() || name.ends_with(PARTIAL_DOWNLOAD_SUFFIX) {
+            continue;
+        }
+        downloads.push(Download {
+            name,
+            size_bytes: metadata.len(),
+        });
+    }
+    downloads.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(downloads)
+}
+
+async fn list_downloads(State(downloads): State<DownloadsDir>) -> Response {
+    let Some(dir) = downloads.get() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    match read_downloads(&dir).await {
+        Ok(downloads) => Json(downloads).into_response(),
+        Err(e) => {
+            warn!("Failed to list downloads in {:?}: {:?}", dir, e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+async fn get_download(
+    State(downloads): State<DownloadsDir>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Response {
+    // Only plain names of completed downloads, nothing outside of the directory
+    if name.starts_with('.')
+        || name.contains(['/', '\\'])
+        || name.ends_with(PARTIAL_DOWNLOAD_SUFFIX)
+    {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let Some(dir) = downloads.get() else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let (file, len) = match open_download(&dir.join(&name)).await {
+        Ok(download) => download,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(e) => {
+            warn!("Failed to read download {:?}: {:?}", name, e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, len.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", name.replace('"', "")),
+            ),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    )
+        .into_response()
+}
+
+/// A download and its size, streamed so large files are not held in memory
+async fn open_download(path: &Path) -> std::io::Result<(tokio::fs::File, u64)> {
+    let file = tokio::fs::File::open(path).await?;
+    let metadata = file.metadata().await?;
+    if !metadata.is_file() {
+        return Err(std::io::ErrorKind::NotFound.into());
+    }
+    Ok((file, metadata.len()))
+}
+
+/// Lists the completed downloads as JSON at `/downloads` and serves them at `/downloads/<name>`
+pub fn router(downloads: DownloadsDir) -> Router {
+    Router::new()
+        .route("/downloads", get(list_downloads))
+        .route("/downloads/{name}", get(get_download))
+        .with_state(downloads)
+}
@@ -1,23 +1,49 @@
+mod saved_profiles;
+mod sessions;
+mod tenants;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
 use clap::Parser;
 use tokio_util::sync::CancellationToken;
 use tonic::{Request, transport::Channel};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use shared::instance_manager::get_service_client::GetServiceClient;
 use shared::instance_manager::try_service_client::TryServiceClient;
+use shared::instance_manager::{AllInstancesQuery, InstanceType, KillReason, Labels, Relationship};
 use shared::instance_manager::{
-    AllInstancesQuery, InstanceType, KillInstanceRequest, KillReason, Relationship,
+    HealthCheck, InstanceDescription, InstanceId, Services, TimestampMs,
 };
-use shared::instance_manager::{HealthCheck, InstanceDescription, InstanceId, TimestampMs};
+use shared::socket_gateway::access_log::AccessLog;
+use shared::socket_gateway::bandwidth::{RateLimit, TrafficLimits};
+use shared::socket_gateway::forwarding::{ForwardedFor, TraceParent};
 use shared::socket_gateway::http_proxy::{
-    HttpProxyConfigTrait, HttpProxyInstance, ServerConnectionManagerTrait,
+    ConnectionContext, HttpProxyConfigTrait, HttpProxyInstance, ServerConnectionManagerTrait,
 };
+use shared::socket_gateway::recording::SessionRecorder;
 use shared::socket_gateway::simple_gateway::{
-    HttpProxyConfig, PathOverride, start_simple_http_gateway_with_proxy_config,
+    GatewayOptions, HttpProxyConfig, PathOverride, UpstreamTimeouts,
+    start_http_gateway_with_options,
 };
-use shared::{add_version, get_timestamp_ms};
+use shared::socket_gateway::tls::load_tls_acceptor;
+use shared::{add_version, get_timestamp_ms, parse_labels};
+
+use instance_container::{RECYCLE_LABEL, SAVED_PROFILES_LABEL};
+use sessions::{SESSION_HEADER, SessionRef, Sessions, parse_session_route};
+use tenants::{TenantLimits, Tenants};
 
 const INSTANCE_ID_PREFIX: &str = "ephemeral-browser-proxy";
+/// Lets the browser side correlate requests with the instance and session serving them
+const INSTANCE_ID_HEADER: &str = "X-WayPoint-Instance-Id";
+const SESSION_ID_HEADER: &str = "X-WayPoint-Session-Id";
+/// Labels a new browser must have, e.g. its launch profile, as `key:value,key:value`
+const LABELS_HEADER: &str = "X-WayPoint-Labels";
+const LABELS_QUERY_PARAM: &str = "labels";
+/// Browsers tried for a new session before the client gets an error
+const MAX_INSTANCE_ATTEMPTS: usize = 3;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -33,6 +59,62 @@ struct Args {
     /// Hostname for metrics
     #[clap(long, env = "HOSTNAME")]
     instance_id: Option<String>,
+    /// PEM certificate chain, enables TLS (`wss://`) on the CDP and Tzafonwright ports
+    #[clap(long, requires = "tls_key_path")]
+    tls_cert_path: Option<PathBuf>,
+    /// PEM private key matching `--tls-cert-path`
+    #[clap(long, requires = "tls_cert_path")]
+    tls_key_path: Option<PathBuf>,
+    /// File of tenant api keys, when set every connection must present a valid key
+    #[clap(long)]
+    api_key_file: Option<PathBuf>,
+    /// Concurrent browsers per tenant, unless overridden in the api key file
+    #[clap(long, default_value_t = 10)]
+    tenant_max_browsers: usize,
+    /// New connections per minute per tenant, unless overridden in the api key file
+    #[clap(long, default_value_t = 60)]
+    tenant_max_connections_per_minute: usize,
+    /// Seconds running sessions are given to finish on shutdown before they are closed
+    #[clap(long, default_value_t = 30)]
+    drain_period_secs: u64,
+    /// Expect a PROXY protocol header on every connection, for running behind a L4 load balancer
+    #[clap(long)]
+    proxy_protocol: bool,
+    /// File to write a JSON line per connection to, `-` for stdout
+    #[clap(long)]
+    access_log: Option<PathBuf>,
+    /// Milliseconds a browser has to accept the connection
+    #[clap(long, default_value_t = 3_000)]
+    connect_timeout_ms: u64,
+    /// Milliseconds a browser has to start answering a request
+    #[clap(long, default_value_t = 5_000)]
+    first_byte_timeout_ms: u64,
+    /// Milliseconds a browser has to complete the websocket handshake
+    #[clap(long, default_value_t = 10_000)]
+    handshake_timeout_ms: u64,
+    /// Milliseconds a new session has to get a browser, including loading its saved profile and
+    /// trying other browsers after failures
+    #[clap(long, default_value_t = 120_000)]
+    allocation_timeout_ms: u64,
+    /// Seconds a browser stays reserved after its client disconnected, so the client can
+    /// reconnect to it with its session token
+    #[clap(long, default_value_t = 0)]
+    session_grace_period_secs: u64,
+    /// Bytes per second in each direction of a single connection
+    #[clap(long)]
+    connection_max_bytes_per_second: Option<u64>,
+    /// Bytes in both directions after which a connection is closed
+    #[clap(long)]
+    connection_max_total_bytes: Option<u64>,
+    /// Bytes per second in each direction shared by all connections of a tenant
+    #[clap(long)]
+    tenant_max_bytes_per_second: Option<u64>,
+    /// Directory to record the websocket messages of every connection to, one file per connection
+    #[clap(long)]
+    record_dir: Option<PathBuf>,
+    /// Only record connections of these tenants
+    #[clap(long, requires = "record_dir")]
+    record_tenant: Vec<String>,
     #[clap(flatten)]
     instance_manager: instance_manager::ClientArgs,
 }
@@ -41,20 +123,55 @@ enum ProxyType {
     CDP,
     TZAFONWRIGHT,
 }
+impl ProxyType {
+    fn name(&self) -> &'static str {
+        match self {
+            ProxyType::CDP => "cdp",
+            ProxyType::TZAFONWRIGHT => "tzafonwright",
+        }
+    }
+}
+/// Where and for which tenants connections are recorded
+#[derive(Clone)]
+struct RecordingConfig {
+    dir: PathBuf,
+    /// All tenants when empty
+    tenants: Vec<String>,
+}
 struct ChromeWarmpoolProxyConfig {
     channel: Channel,
     instance_id: InstanceId,
     proxy_type: ProxyType,
+    tenants: Option<Tenants>,
+    sessions: Sessions,
+    timeouts: UpstreamTimeouts,
+    /// Deadline for all attempts at getting a browser for a new session
+    allocation_timeout: Duration,
+    /// Limits of every connection, the rate limiter of its tenant is added to them
+    traffic_limits: TrafficLimits,
+    recording: Option<RecordingConfig>,
+}
+
+struct InstanceConnection {
+    request: shared::socket_gateway::http_proxy::Request,
+    server: tokio::net::TcpStream,
+    response: Option<shared::socket_gateway::http_proxy::Response>,
 }
 
 struct ServerConnectionManager {
     instance_id: String,
-    channel: Channel,
+    tenant_id: Option<String>,
+    client_addr: SocketAddr,
+    session_token: String,
+    sessions: Sessions,
 }
 
 impl ServerConnectionManagerTrait for ServerConnectionManager {
     async fn on_open(&mut self) -> Result<(), shared::socket_gateway::http_proxy::Error> {
-        info!("Connected to instance: {}", self.instance_id);
+        info!(
+            "Connected to instance: {} tenant: {:?} client: {}",
+            self.instance_id, self.tenant_id, self.client_addr
+        );
         Ok(())
     }
 
@@ -66,30 +183,31 @@ impl ServerConnectionManagerTrait for ServerConnectionManager {
         if let Err(e) = close_result {
             error!("Error in on_close: {:?}", e);
         }
-        let mut service_client =
-            TryServiceClient::with_interceptor(self.channel.clone(), add_version);
-        service_client
-            .try_update_instance_description(Request::new(InstanceDescription {
-                instance_id: Some(InstanceId {
-                    instance_id: self.instance_id.clone(),
-                }),
-                kill_instance_request: Some(KillInstanceRequest {
-                    kill_reason: KillReason::Killed as i32,
-                    timestamp_ms: None,
-                }),
-                ..Default::default()
-            }))
-            .await
-            .map_err(|_| {
-                shared::socket_gateway::http_proxy::Error::IoError("Failed to kill instance")
-            })?;
-        Ok(())
+        self.sessions.release(&self.session_token).await
     }
 }
 
+/// Takes the labels a new browser is requested with from the `X-WayPoint-Labels` header or
+/// `labels` query parameter
+fn labels_from_request(
+    request: &mut shared::socket_gateway::http_proxy::Request,
+) -> Result<Option<Labels>, shared::socket_gateway::http_proxy::Error> {
+    let labels = request
+        .header(LABELS_HEADER)
+        .or_else(|| request.query_param(LABELS_QUERY_PARAM))
+        .map(parse_labels)
+        .transpose()
+        .map_err(|_| shared::socket_gateway::http_proxy::Error::ParseError("Invalid labels"))?;
+    request.remove_header(LABELS_HEADER);
+    request.remove_query_param(LABELS_QUERY_PARAM);
+    Ok(labels)
+}
+
 impl ChromeWarmpoolProxyConfig {
     async fn get_instance(
         &self,
+        tenant_id: Option<String>,
+        labels: Option<Labels>,
     ) -> Result<InstanceDescription, shared::socket_gateway::http_proxy::Error> {
         let mut client = GetServiceClient::with_interceptor(self.channel.clone(), add_version);
         let mut interaction_client =
@@ -97,6 +215,7 @@ impl ChromeWarmpoolProxyConfig {
         for instance in client
             .get_all_instances(Request::new(AllInstancesQuery {
                 instance_type: InstanceType::ChromeBrowser as i32,
+                labels,
             }))
             .await
             .map_err(|_| {
@@ -121,7 +240,7 @@ impl ChromeWarmpoolProxyConfig {
                     health_check:
                         Some(HealthCheck {
                             timestamp_ms: Some(TimestampMs { timestamp_ms }),
-                            ..
+                            unavailable_reason: None,
                         }),
                     parent: None,
                     kill_instance_request: None,
@@ -135,16 +254,17 @@ impl ChromeWarmpoolProxyConfig {
                             }),
                             parent: Some(Relationship {
                                 instance_id: Some(self.instance_id.clone()),
+                                tenant_id: tenant_id.clone(),
                                 ..Default::default()
                             }),
                             ..Default::default()
                         }))
                         .await;
                     info!("Update instance description response: {:?}", res);
-                    if let Ok(response) = res {
-                        if response.into_inner().value {
-                            return Ok(instance_description);
-                        }
+                    if let Ok(response) = res
+                        && response.into_inner().value
+                    {
+                        return Ok(instance_description);
                     }
                 }
                 _ => continue,
@@ -154,59 +274,302 @@ impl ChromeWarmpoolProxyConfig {
             "No available instance found",
         ))
     }
+    /// Recorder for a new connection to the instance, if connections of the tenant are recorded.
+    /// A recording that can't be created only loses the recording, not the connection
+    fn open_recorder(&self, instance_id: &str, tenant_id: Option<&str>) -> Option<SessionRecorder> {
+        let recording = self.recording.as_ref()?;
+        if !recording.tenants.is_empty()
+            && !tenant_id.is_some_and(|tenant_id| recording.tenants.iter().any(|t| t == tenant_id))
+        {
+            return None;
+        }
+        let path = recording.dir.join(format!(
+            "{}-{}-{}.jsonl",
+            get_timestamp_ms().timestamp_ms,
+            instance_id,
+            self.proxy_type.name()
+        ));
+        match SessionRecorder::create(&path) {
+            Ok(recorder) => {
+                info!("Recording connection to {}", path.display());
+                Some(recorder)
+            }
+            Err(e) => {
+                warn!("Failed to record connection to {}: {:?}", path.display(), e);
+                None
+            }
+        }
+    }
     async fn get_proxy_config(
         &self,
-        instance_description: InstanceDescription,
+        services: Services,
+        path: String,
     ) -> Result<HttpProxyConfig, shared::socket_gateway::http_proxy::Error> {
-        let address = instance_description
-            .services
-            .and_then(|service| match self.proxy_type {
-                ProxyType::CDP => service.chrome_debug_port_service,
-                ProxyType::TZAFONWRIGHT => service.tzafonwright_service,
-            })
-            .ok_or(shared::socket_gateway::http_proxy::Error::IoError(
-                "Instance has no address",
-            ))?;
+        let address = match self.proxy_type {
+            ProxyType::CDP => services.chrome_debug_port_service,
+            ProxyType::TZAFONWRIGHT => services.tzafonwright_service,
+        }
+        .ok_or(shared::socket_gateway::http_proxy::Error::IoError(
+            "Instance has no address",
+        ))?;
         let proxy_config = HttpProxyConfig::new(&address)
-            .with_path_override(PathOverride::Replace("/".to_string()))
-            .with_header_override("Host", &address);
+            .with_path_override(PathOverride::Replace(path))
+            .with_header_override("Host", &address)
+            .with_request_hook(ForwardedFor)
+            .with_request_hook(TraceParent)
+            .with_timeouts(self.timeouts);
 
         Ok(proxy_config)
     }
+    /// Connects to a service of the instance, with the handshake done when timeouts are set
+    async fn connect_to_instance(
+        &self,
+        request: shared::socket_gateway::http_proxy::Request,
+        context: &ConnectionContext,
+        instance_id: &str,
+        session_id: Option<&str>,
+        services: Services,
+        path: String,
+    ) -> Result<InstanceConnection, shared::socket_gateway::http_proxy::Error> {
+        let mut proxy_config = self
+            .get_proxy_config(services, path)
+            .await?
+            .with_header_override(INSTANCE_ID_HEADER, instance_id);
+        if let Some(session_id) = session_id {
+            proxy_config = proxy_config.with_header_override(SESSION_ID_HEADER, session_id);
+        }
+        let request = proxy_config.modify_request(request, context).await?;
+        let (mut server, _) = proxy_config.connect(&request).await?;
+        let response = proxy_config.handshake(&request, &mut server).await?;
+        Ok(InstanceConnection {
+            request,
+            server,
+            response,
+        })
+    }
+    /// Attaches the connection to the session of the token, it counts as one of the session's
+    /// connections until it closes. A session route must name the browser of the session
+    async fn connect_to_session(
+        &self,
+        request: shared::socket_gateway::http_proxy::Request,
+        context: &ConnectionContext,
+        token: &str,
+        tenant_id: Option<&str>,
+        route: Option<(String, String)>,
+    ) -> Result<(SessionRef, InstanceConnection), shared::socket_gateway::http_proxy::Error> {
+        let session = self.sessions.resume(token, tenant_id)?;
+        let path = match route {
+            None => Ok("/".to_string()),
+            Some((instance_id, path)) if instance_id == session.instance_id => Ok(path),
+            Some(_) => Err(shared::socket_gateway::http_proxy::Error::NotFound(
+                "Unknown instance",
+            )),
+        };
+        let connected = match path {
+            Ok(path) => {
+                self.connect_to_instance(
+                    request,
+                    context,
+                    &session.instance_id,
+                    Some(&session.id),
+                    session.services.clone(),
+                    path,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match connected {
+            Ok(connection) => Ok((session, connection)),
+            Err(e) => {
+                self.sessions.release(&session.token).await?;
+                Err(e)
+            }
+        }
+    }
+    /// Allocates a browser under a new session, seeded with the saved profile if the request
+    /// names one. A browser failing to connect or answer in time is reported unhealthy and
+    /// another one is tried, a saved profile in use fails right away
+    async fn connect_to_new_instance(
+        &self,
+        mut request: shared::socket_gateway::http_proxy::Request,
+        context: &ConnectionContext,
+        tenant_id: Option<String>,
+        labels: Option<Labels>,
+    ) -> Result<(SessionRef, InstanceConnection), shared::socket_gateway::http_proxy::Error> {
+        let saved_profile = saved_profiles::profile_from_request(&mut request)?;
+        let labels = if saved_profile.is_some() {
+            let mut labels = labels.unwrap_or_default();
+            labels
+                .labels
+                .insert(SAVED_PROFILES_LABEL.to_string(), "true".to_string());
+            Some(labels)
+        } else {
+            labels
+        };
+        let mut browser_slot = match (&self.tenants, &tenant_id) {
+            (Some(tenants), Some(tenant_id)) => Some(tenants.acquire(tenant_id)?),
+            _ => None,
+        };
+        let deadline = tokio::time::Instant::now() + self.allocation_timeout;
+        let mut attempt = 1;
+        loop {
+            // One deadline for all attempts, also cutting off the browser being handed out
+            if tokio::time::Instant::now() >= deadline {
+                return Err(shared::socket_gateway::http_proxy::Error::Timeout(
+                    "Timed out getting a browser",
+                ));
+            }
+            let instance_description = self.get_instance(tenant_id.clone(), labels.clone()).await?;
+            let instance_id = instance_description
+                .instance_id
+                .ok_or(shared::socket_gateway::http_proxy::Error::IoError(
+                    "Instance has no id",
+                ))?
+                .instance_id;
+            let services = instance_description.services.unwrap_or_default();
+            let recyclable = instance_description.labels.is_some_and(|labels| {
+                labels
+                    .labels
+                    .get(RECYCLE_LABEL)
+                    .is_some_and(|v| v == "true")
+            });
+            let session = self.sessions.create(
+                instance_id.clone(),
+                services.clone(),
+                tenant_id.clone(),
+                browser_slot,
+                recyclable,
+                saved_profile.is_some(),
+            )?;
+            let connected = match tokio::time::timeout_at(deadline, async {
+                match (&saved_profile, &services.saved_profile_service) {
+                    (None, _) => {}
+                    (Some(name), Some(address)) => {
+                        saved_profiles::load(address, tenant_id.as_deref(), name).await?
+                    }
+                    (Some(_), None) => {
+                        return Err(shared::socket_gateway::http_proxy::Error::IoError(
+                            "Instance has no saved profile service",
+                        ));
+                    }
+                }
+                self.connect_to_instance(
+                    request.clone(),
+                    context,
+                    &instance_id,
+                    Some(&session.id),
+                    services,
+                    "/".to_string(),
+                )
+                .await
+            })
+            .await
+            {
+                Ok(connected) => connected,
+                // The client ran out of time, not the browser, so it is not reported unhealthy
+                Err(_) => {
+                    warn!(
+                        "Timed out getting a browser, giving up on instance {}",
+                        instance_id
+                    );
+                    self.sessions
+                        .discard(&session.token, KillReason::Killed)
+                        .await?;
+                    return Err(shared::socket_gateway::http_proxy::Error::Timeout(
+                        "Timed out getting a browser",
+                    ));
+                }
+            };
+            match connected {
+                Ok(connection) => return Ok((session, connection)),
+                // Another browser would find the profile in use just the same
+                Err(e @ shared::socket_gateway::http_proxy::Error::Conflict(_)) => {
+                    self.sessions
+                        .discard(&session.token, KillReason::Killed)
+                        .await?;
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!(
+                        "Instance {} failed on connect, attempt {}: {:?}",
+                        instance_id, attempt, e
+                    );
+                    browser_slot = self
+                        .sessions
+                        .discard(&session.token, KillReason::HealthCheckFailed)
+                        .await?;
+                    if attempt >= MAX_INSTANCE_ATTEMPTS || tokio::time::Instant::now() >= deadline {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                }
+            }
+        }
+    }
 }
 
 impl HttpProxyConfigTrait<ServerConnectionManager> for ChromeWarmpoolProxyConfig {
     async fn new_connection(
-        &mut self,
-        request: shared::socket_gateway::http_proxy::Request,
+        &self,
+        mut request: shared::socket_gateway::http_proxy::Request,
+        context: &ConnectionContext,
     ) -> Result<
         shared::socket_gateway::http_proxy::HttpProxyInstance<ServerConnectionManager>,
         shared::socket_gateway::http_proxy::Error,
     > {
-        let instance_description = self.get_instance().await?;
-        let instance_id = instance_description
-            .clone()
-            .instance_id
-            .ok_or(shared::socket_gateway::http_proxy::Error::IoError(
-                "Instance has no id",
-            ))?
-            .instance_id;
-        let proxy_config = self.get_proxy_config(instance_description).await?;
-        let request = proxy_config.modify_request(request).await?;
+        // Every connection counts against the rate, also those to a browser the tenant holds
+        let tenant_id = match &self.tenants {
+            Some(tenants) => {
+                let tenant_id = tenants.authenticate(&mut request)?;
+                tenants.check_connection_rate(&tenant_id)?;
+                Some(tenant_id)
+            }
+            None => None,
+        };
+        let traffic_limits = match (&self.tenants, &tenant_id) {
+            (Some(tenants), Some(tenant_id)) => match tenants.rate_limiter(tenant_id)? {
+                Some(rate_limiter) => self.traffic_limits.clone().with_shared(rate_limiter),
+                None => self.traffic_limits.clone(),
+            },
+            _ => self.traffic_limits.clone(),
+        };
+        // Taken before the route is parsed, so the token is not forwarded as part of the path
+        let token = Sessions::token_from_request(&mut request);
+        let (session, connection) = match (parse_session_route(&request.path), token) {
+            // Knowing the instance id is not enough to reach a browser, only its session grants access
+            (Some(_), None) => {
+                return Err(shared::socket_gateway::http_proxy::Error::Unauthorized(
+                    "Missing session token",
+                ));
+            }
+            (route, Some(token)) => {
+                self.connect_to_session(request, context, &token, tenant_id.as_deref(), route)
+                    .await?
+            }
+            (None, None) => {
+                let labels = labels_from_request(&mut request)?;
+                self.connect_to_new_instance(request, context, tenant_id.clone(), labels)
+                    .await?
+            }
+        };
 
+        let recorder = self.open_recorder(&session.instance_id, tenant_id.as_deref());
         Ok(HttpProxyInstance {
-            request,
-            server: tokio::net::TcpStream::connect(&proxy_config.server_addr)
-                .await
-                .map_err(|_| {
-                    shared::socket_gateway::http_proxy::Error::IoError(
-                        "Failed to connect to instance",
-                    )
-                })?,
+            request: connection.request,
+            server: connection.server,
             manager: ServerConnectionManager {
-                instance_id,
-                channel: self.channel.clone(),
+                instance_id: session.instance_id.clone(),
+                tenant_id,
+                client_addr: context.peer_addr,
+                session_token: session.token.clone(),
+                sessions: self.sessions.clone(),
             },
+            response_headers: vec![(SESSION_HEADER.to_string(), session.token)],
+            instance_id: Some(session.instance_id),
+            response: connection.response,
+            traffic_limits,
+            recorder,
         })
     }
 }
@@ -217,6 +580,8 @@ async fn main() -> anyhow::Result<()> {
     tracing_subscriber::fmt()
         // .with_max_level(Level::TRACE)
         .init();
+    // Listened for from the start, so a signal during startup is not lost
+    let shutdown_signal = shared::utils::shutdown_signal()?;
     let instance_id = InstanceId {
         instance_id: shared::utils::generate_instance_id(INSTANCE_ID_PREFIX),
     };
@@ -228,37 +593,136 @@ async fn main() -> anyhow::Result<()> {
         &instance_id,
         &InstanceType::WarmpoolChromeProxy,
         &None,
+        &None,
+        None,
         &channel,
         &cancellation_token,
     )
     .await
     .map_err(|e| anyhow::anyhow!("Failed to start health loop: {:?}", e))?;
 
-    start_simple_http_gateway_with_proxy_config(
+    let tenants = args
+        .api_key_file
+        .as_ref()
+        .map(|path| {
+            Tenants::load(
+                path,
+                TenantLimits {
+                    max_concurrent_browsers: args.tenant_max_browsers,
+                    max_connections_per_minute: args.tenant_max_connections_per_minute,
+                    max_bytes_per_second: args.tenant_max_bytes_per_second,
+                },
+            )
+        })
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("Failed to load api keys: {:?}", e))?;
+
+    let gateway_options =
+        GatewayOptions::new().with_drain_period(Duration::from_secs(args.drain_period_secs));
+    let gateway_options = if args.proxy_protocol {
+        gateway_options.with_proxy_protocol()
+    } else {
+        gateway_options
+    };
+    let gateway_options = match &args.access_log {
+        Some(path) => gateway_options.with_access_log(
+            AccessLog::open(path)
+                .map_err(|e| anyhow::anyhow!("Failed to open access log: {:?}", e))?,
+        ),
+        None => gateway_options,
+    };
+    let gateway_options = match (&args.tls_cert_path, &args.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => gateway_options.with_tls(
+            load_tls_acceptor(cert_path, key_path)
+                .map_err(|e| anyhow::anyhow!("Failed to load TLS config: {:?}", e))?,
+        ),
+        _ => gateway_options,
+    };
+
+    // Shared by both gateways, so a session started on one port can be continued on the other
+    let sessions = Sessions::new(
+        Duration::from_secs(args.session_grace_period_secs),
+        instance_id.clone(),
+        channel.clone(),
+    );
+    sessions.start_reaper(&cancellation_token);
+
+    let timeouts = UpstreamTimeouts {
+        connect: Some(Duration::from_millis(args.connect_timeout_ms)),
+        first_byte: Some(Duration::from_millis(args.first_byte_timeout_ms)),
+        handshake: Some(Duration::from_millis(args.handshake_timeout_ms)),
+    };
+    let mut traffic_limits = TrafficLimits::new();
+    if let Some(max_bytes_per_second) = args.connection_max_bytes_per_second {
+        let limit = RateLimit::per_second(max_bytes_per_second);
+        traffic_limits = traffic_limits.with_upload(limit).with_download(limit);
+    }
+    if let Some(max_total_bytes) = args.connection_max_total_bytes {
+        traffic_limits = traffic_limits.with_max_total_bytes(max_total_bytes);
+    }
+    let recording = args.record_dir.map(|dir| RecordingConfig {
+        dir,
+        tenants: args.record_tenant,
+    });
+    let cdp_gateway = start_http_gateway_with_options(
         ChromeWarmpoolProxyConfig {
             channel: channel.clone(),
             instance_id: instance_id.clone(),
             proxy_type: ProxyType::CDP,
+            tenants: tenants.clone(),
+            sessions: sessions.clone(),
+            timeouts,
+            allocation_timeout: Duration::from_millis(args.allocation_timeout_ms),
+            traffic_limits: traffic_limits.clone(),
+            recording: recording.clone(),
         },
         format!("0.0.0.0:{}", args.cdp_port)
             .parse()
             .map_err(|_| anyhow::anyhow!("Failed to parse listen address"))?,
+        gateway_options.clone(),
         &cancellation_token,
     )
     .await?;
-    start_simple_http_gateway_with_proxy_config(
+    let tzafonwright_gateway = start_http_gateway_with_options(
         ChromeWarmpoolProxyConfig {
             channel: channel.clone(),
             instance_id: instance_id.clone(),
             proxy_type: ProxyType::TZAFONWRIGHT,
+            tenants,
+            sessions: sessions.clone(),
+            timeouts,
+            allocation_timeout: Duration::from_millis(args.allocation_timeout_ms),
+            traffic_limits,
+            recording,
         },
         format!("0.0.0.0:{}", args.tzafonwright_port)
             .parse()
             .map_err(|_| anyhow::anyhow!("Failed to parse listen address"))?,
+        gateway_options,
         &cancellation_token,
     )
     .await?;
 
-    cancellation_token.cancelled().await;
+    tokio::select! {
+        _ = cancellation_token.cancelled() => {}
+        signal = shutdown_signal => {
+            info!("Received {}, shutting down", signal);
+            cancellation_token.cancel();
+        }
+    }
+    // Deregister first so no new browsers are attached to this proxy, its running sessions
+    // keep their browsers until they are drained
+    if let Err(e) = shared::utils::kill_instance(&instance_id, KillReason::Shutdown, &channel).await
+    {
+        error!("Failed to deregister proxy: {:?}", e);
+    }
+    for gateway in [cdp_gateway, tzafonwright_gateway] {
+        if let Err(e) = gateway.await {
+            error!("Gateway failed while draining: {:?}", e);
+        }
+    }
+    // Browsers kept for a reconnect are not needed anymore
+    sessions.end_all().await;
+    info!("Proxy shut down");
     Ok(())
 }
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use shared::instance_manager::Labels;
+
+const DEFAULT_PROFILE_NAME: &str = "default";
+const DEFAULT_WINDOW_SIZE: (u32, u32) = (1280, 720);
+const DEFAULT_XVFB_DISPLAY: u32 = 99;
+
+/// How Chrome is launched. Read from a JSON file with the same field names, options given on
+/// the command line take precedence over the file
+#[derive(clap::Args, Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LaunchProfile {
+    /// Name clients request browsers of this profile by, with the `profile` label
+    #[clap(long = "profile-name")]
+    pub name: Option<String>,
+    /// Window size as WIDTHxHEIGHT, 1280x720 when unset
+    #[clap(long)]
+    pub window_size: Option<String>,
+    #[clap(long)]
+    pub user_agent: Option<String>,
+    /// Browser language, e.g. de-DE
+    #[clap(long)]
+    pub locale: Option<String>,
+    /// IANA timezone, e.g. Europe/Berlin
+    #[clap(long)]
+    pub timezone: Option<String>,
+    /// Proxy for all browser traffic, e.g. http://proxy:3128
+    #[clap(long)]
+    pub proxy_server: Option<String>,
+    /// Unpacked extension directory to load, can be repeated
+    #[clap(long = "extension")]
+    pub extensions: Vec<PathBuf>,
+    /// Chrome flag added after the others, can be repeated
+    #[clap(long = "extra-chrome-flag", allow_hyphen_values = true)]
+    pub extra_flags: Vec<String>,
+    /// Run Chrome headful on an Xvfb display instead of headless
+    #[clap(long)]
+    pub headful: bool,
+    /// X display number Xvfb is started on when headful, 99 when unset
+    #[clap(long)]
+    pub xvfb_display: Option<u32>,
+}
+
+impl LaunchProfile {
+    /// Reads the profile file, if any, and applies the command line options on top of it
+    pub fn load(self, path: Option<&Path>) -> anyhow::Result<Self> {
+        let file = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read launch profile {:?}", path))?;
+                serde_json::from_str(&content)
+                    .with_context(|| format!("Invalid launch profile {:?}", path))?
+            }
+            None => LaunchProfile::default(),
+        };
+        let profile = self.merge(file);
+        profile.window_size()?;
+        Ok(profile)
+    }
+
+    fn merge(self, file: LaunchProfile) -> Self {
+        Self {
+            name: self.name.or(file.name),
+            window_size: self.window_size.or(file.window_size),
+            user_agent: self.user_agent.or(file.user_agent),
+            locale: self.locale.or(file.locale),
+            timezone: self.timezone.or(file.timezone),
+            proxy_server: self.proxy_server.or(file.proxy_server),
+            extensions: [file.extensions, self.extensions].concat(),
+            extra_flags: [file.extra_flags, self.extra_flags].concat(),
+            headful: self.headful || file.headful,
+            xvfb_display: self.xvfb_display.or(file.xvfb_display),
+        }
+    }
+
+    pub fn window_size(&self) -> anyhow::Result<(u32, u32)> {
+        let Some(window_size) = &self.window_size else {
+            return Ok(DEFAULT_WINDOW_SIZE);
+        };
+        window_size
+            .split_once('x')
+            .and_then(|(width, height)| Some((width.parse().ok()?, height.parse().ok()?)))
+            .ok_or_else(|| anyhow::anyhow!("Window size {:?} is not WIDTHxHEIGHT", window_size))
+    }
+
+    pub fn xvfb_display(&self) -> u32 {
+        self.xvfb_display.unwrap_or(DEFAULT_XVFB_DISPLAY)
+    }
+
+    /// Chrome flags of the profile, the window size is expected to be valid
+    pub fn chrome_flags(&self) -> Vec<String> {
+        let (width, height) = self.window_size().unwrap_or(DEFAULT_WINDOW_SIZE);
+        let mut flags = vec![format!("--window-size={},{}", width, height)];
+        if !self.headful {
+            flags.push("--headless".to_string());
+        }
+        if let Some(user_agent) = &self.user_agent {
+            flags.push(format!("--user-agent={}", user_agent));
+        }
+        if let Some(locale) = &self.locale {
+            flags.push(format!("--lang={}", locale));
+        }
+        if let Some(proxy_server) = &self.proxy_server {
+            flags.push(format!("--proxy-server={}", proxy_server));
+        }
+        if self.extensions.is_empty() {
+            flags.push("--disable-extensions".to_string());
+        } else {
+            let extensions = self
+                .extensions
+                .iter()
+                .map(|extension| extension.display().to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            flags.push(format!("--disable-extensions-except={}", extensions));
+            flags.push(format!("--load-extension={}", extensions));
+        }
+        flags.extend(self.extra_flags.iter().cloned());
+        flags
+    }
+
+    /// Effective profile reported to the instance manager, so clients can request browsers by it
+    pub fn labels(&self) -> Labels {
+        let (width, height) = self.window_size().unwrap_or(DEFAULT_WINDOW_SIZE);
+        let mut labels = HashMap::from([
+            (
+                "profile".to_string(),
+                self.name
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_PROFILE_NAME.to_string()),
+            ),
+            ("window_size".to_string(), format!("{}x{}", width, height)),
+            ("headless".to_string(), (!self.headful).to_string()),
+        ]);
+        for (key, value) in [
+            ("user_agent", &self.user_agent),
+            ("locale", &self.locale),
+            ("timezone", &self.timezone),
+            ("proxy_server", &self.proxy_server),
+        ] {
+            if let Some(value) = value {
+                labels.insert(key.to_string(), value.clone());
+            }
+        }
+        if !self.extensions.is_empty() {
+            let extensions = self
+                .extensions
+                .iter()
+                .filter_map(|extension| extension.file_name())
+                .map(|name| name.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            labels.insert("extensions".to_string(), extensions);
+        }
+        Labels { labels }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_launch_profile() {
+        let file: LaunchProfile = serde_json::from_str(
+            r#"{"name": "de", "locale": "de-DE", "window_size": "1920x1080", "extra_flags": ["--a"]}"#,
+        )
+        .unwrap();
+        let cli = LaunchProfile {
+            window_size: Some("800x600".to_string()),
+            extra_flags: vec!["--b".to_string()],
+            ..Default::default()
+        };
+        let profile = cli.merge(file);
+        assert_eq!(profile.window_size().unwrap(), (800, 600));
+        let flags = profile.chrome_flags();
+        assert_eq!(flags[0], "--window-size=800,600");
+        assert!(flags.contains(&"--headless".to_string()));
+        assert!(flags.contains(&"--lang=de-DE".to_string()));
+        assert!(flags.ends_with(&["--a".to_string(), "--b".to_string()]));
+
+        let labels = profile.labels().labels;
+        assert_eq!(labels["profile"], "de");
+        assert_eq!(labels["window_size"], "800x600");
+        assert_eq!(labels["headless"], "true");
+
+        let invalid = LaunchProfile {
+            window_size: Some("wide".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.load(None).is_err());
+    }
+}
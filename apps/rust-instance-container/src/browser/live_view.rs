@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+use axum::Router;
+use axum::body::Body;
+use axum::extract::State;
+use axum::http::{StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::{Value, json};
+use tokio::io::{AsyncWriteExt, DuplexStream};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use shared::socket_gateway::simple_gateway::{GatewayTarget, PathOverride};
+use shared::socket_gateway::websocket::{OPCODE_TEXT, WebSocketReader, write_message};
+
+use crate::readiness::{cdp_command, open_websocket};
+
+const SCREENSHOT_PATH: &str = "/screenshot";
+const LIVE_PATH: &str = "/live";
+const SCREENSHOT_TIMEOUT: Duration = Duration::from_secs(10);
+const FRAME_BOUNDARY: &str = "frame";
+/// JPEG quality of the screencast, it is for looking at a session, not for recording it
+const SCREENCAST_QUALITY: u8 = 60;
+/// Bytes buffered for a client reading the screencast slower than Chrome sends it
+const SCREENCAST_BUFFER: usize = 1024 * 1024;
+
+/// The page the operator is shown, and when to end running screencasts
+#[derive(Clone)]
+struct LiveView {
+    /// Follows Chrome when it is restarted
+    target: GatewayTarget,
+    cancellation_token: CancellationToken,
+}
+
+/// Opens the CDP websocket of the first page of the running Chrome
+async fn open_page(
+    target: &GatewayTarget,
+) -> anyhow::Result<(WebSocketReader<OwnedReadHalf>, OwnedWriteHalf)> {
+    let Some((addr, PathOverride::ReplaceRoot(path))) = target.get() else {
+        anyhow::bail!("No Chrome running");
+    };
+    let (mut reader, mut write_half) = open_websocket(&addr, &path).await?;
+    let targets = cdp_command(
+        &mut reader,
+        &mut write_half,
+        1,
+        "Target.getTargets",
+        json!({}),
+    )
+    .await?;
+    let target_id = targets
+        .get("targetInfos")
+        .and_then(Value::as_array)
+        .and_then(|targets| {
+            targets
+                .iter()
+                .find(|target| target.get("type").and_then(Value::as_str) == Some("page"))
+        })
+        .and_then(|target| target.get("targetId"))
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow::anyhow!("No page open"))?;
+    open_websocket(&addr, &format!("/devtools/page/{}", target_id)).await
+}
+
+async fn capture_screenshot(target: &GatewayTarget) -> anyhow::Result<Vec<u8>> {
+    let (mut reader, mut write_half) = open_page(target).await?;
+    let screenshot = cdp_command(
+        &mut reader,
+        &mut write_half,
+        1,
+        "Page.captureScreenshot",
+        json!({"format": "png"}),
+    )
+    .await?;
+    let data = screenshot
+        .get("data")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow::anyhow!("Page.captureScreenshot returned no data"))?;
+    Ok(STANDARD.decode(data)?)
+}
+
+async fn screenshot(State(live_view): State<LiveView>) -> Response {
+    match tokio::time::timeout(SCREENSHOT_TIMEOUT, capture_screenshot(&live_view.target)).await {
+        Ok(Ok(png)) => ([(header::CONTENT_TYPE, "image/png")], png).into_response(),
+        Ok(Err(e)) => {
+            warn!("Failed to capture screenshot: {:?}", e);
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+        Err(_) => StatusCode::GATEWAY_TIMEOUT.into_response(),
+    }
+}
+
+/// Writes the screencast frames of the page as parts of a multipart response until the client
+/// goes away or the page closes
+async fn stream_screencast(
+    mut reader: WebSocketReader<OwnedReadHalf>,
+    mut write_half: OwnedWriteHalf,
+    mut out: DuplexStream,
+) -> anyhow::Result<()> {
+    cdp_command(
+        &mut reader,
+        &mut write_half,
+        1,
+        "Page.startScreencast",
+        json!({"format": "jpeg", "quality": SCREENCAST_QUALITY}),
+    )
+    .await?;
+    let mut id = 2;
+    loop {
+        let (_, payload) = reader
+            .next()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read screencast: {:?}", e))?
+            .ok_or_else(|| anyhow::anyhow!("Chrome closed the screencast"))?;
+        let Ok(message) = serde_json::from_slice::<Value>(&payload) else {
+            continue;
+        };
+        if message.get("method").and_then(Value::as_str) != Some("Page.screencastFrame") {
+            continue;
+        }
+        let Some(params) = message.get("params") else {
+            continue;
+        };
+        let frame = STANDARD.decode(params.get("data").and_then(Value::as_str).unwrap_or(""))?;
+        let part_header = format!(
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            FRAME_BOUNDARY,
+            frame.len()
+        );
+        out.write_all(part_header.as_bytes()).await?;
+        out.write_all(&frame).await?;
+        out.write_all(b"\r\n").await?;
+        // Chrome sends the next frame once this one is acknowledged, so a slow client slows
+        // the screencast down instead of piling up frames
+        let ack = json!({
+            "id": id,
+            "method": "Page.screencastFrameAck",
+            "params": {"sessionId": params.get("sessionId")},
+        });
+        write_message(&mut write_half, OPCODE_TEXT, ack.to_string().into_bytes())
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to acknowledge frame: {:?}", e))?;
+        id += 1;
+    }
+}
+
+async fn live(State(live_view): State<LiveView>) -> Response {
+    let (reader, write_half) = match open_page(&live_view.target).await {
+        Ok(page) => page,
+        Err(e) => {
+            warn!("Failed to open page for screencast: {:?}", e);
+            return StatusCode::SERVICE_UNAVAILABLE.into_response();
+        }
+    };
+    let (out, body) = tokio::io::duplex(SCREENCAST_BUFFER);
+    tokio::spawn(async move {
+        // The response never ends on its own, it would hold up the shutdown of the endpoint
+        tokio::select! {
+            _ = live_view.cancellation_token.cancelled() => {}
+            result = stream_screencast(reader, write_half, out) => {
+                debug!("Screencast ended: {:?}", result);
+            }
+        }
+    });
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                format!("multipart/x-mixed-replace; boundary={}", FRAME_BOUNDARY),
+            ),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(body)),
+    )
+        .into_response()
+}
+
+/// Serves a PNG screenshot of the first page at `/screenshot` and its MJPEG screencast at `/live`
+pub fn router(target: GatewayTarget, cancellation_token: &CancellationToken) -> Router {
+    Router::new()
+        .route(SCREENSHOT_PATH, get(screenshot))
+        .route(LIVE_PATH, get(live))
+        .with_state(LiveView {
+            target,
+            cancellation_token: cancellation_token.clone(),
+        })
+}
@@ -1,21 +1,44 @@
 mod chrome;
+mod downloads;
+mod launch_profile;
+mod live_view;
+mod profile_store;
+mod readiness;
 mod tzafonwright;
 
 use anyhow::Context;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use instance_container::{SharedArgs, get_ip_address, create_services_from_args, instance_manager_connection};
+use instance_container::child_process::RestartPolicy;
+use instance_container::{
+    RECYCLE_LABEL, SAVED_PROFILES_LABEL, SharedArgs, create_services_from_args, get_ip_address,
+    instance_has_parent, instance_manager_connection,
+};
 
 use clap::Parser;
 use shared::socket_gateway::simple_gateway::{
-    HttpProxyConfig, PathOverride, start_simple_http_gateway_with_proxy_config,
+    GatewayOptions, GatewayTarget, HttpProxyConfig, PathOverride, start_http_gateway_with_options,
 };
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
-use tracing::info;
+use tonic::transport::Channel;
+use tracing::{error, info, warn};
+
+use chrome::{ChromeProcess, LaunchDir};
+use downloads::DownloadsDir;
+use launch_profile::LaunchProfile;
+use profile_store::{ProfileCommand, ProfileKey, ProfileStore};
+use readiness::BrowserLiveness;
 
-use shared::instance_manager::{InstanceId, InstanceType};
+use shared::instance_manager::{InstanceId, InstanceType, KillReason};
+use shared::utils::{Availability, Liveness};
 
 const INSTANCE_ID_PREFIX: &str = "browser-container";
+/// How long Chrome may take to write its profile and exit once asked to close
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -28,17 +51,433 @@ struct Args {
     /// Tzafonwright port
     #[clap(long, default_value_t = 1337)]
     tzafonwright_port: u16,
+    /// Port of the HTTP endpoint serving the files the browser downloaded, screenshots and a
+    /// screencast of the browser and, with a profile store, loading and saving named profiles
+    #[clap(long, default_value_t = 8080)]
+    http_port: u16,
     /// Path to the Tzafonwright binary
     #[clap(long, default_value = "/app/tzafonwright")]
     tzafonwright_binary_path: PathBuf,
+    /// Times Chrome is restarted in place within `--chrome-crash-window-secs` when it exits
+    /// while the browser is not attached to a session, 0 kills the container on the first exit
+    #[clap(long, default_value_t = 3)]
+    max_chrome_restarts: usize,
+    #[clap(long, default_value_t = 300)]
+    chrome_crash_window_secs: u64,
+    /// Times Tzafonwright is restarted within `--tzafonwright-crash-window-secs` when it exits,
+    /// the container exits once it may not be restarted anymore
+    #[clap(long, default_value_t = 5)]
+    max_tzafonwright_restarts: usize,
+    #[clap(long, default_value_t = 300)]
+    tzafonwright_crash_window_secs: u64,
+    /// Seconds Chrome and Tzafonwright are given to answer the readiness probe, the container
+    /// exits when they don't
+    #[clap(long, default_value_t = 60)]
+    startup_timeout_secs: u64,
+    /// Seconds between liveness checks of Chrome and Tzafonwright gating the heartbeat
+    #[clap(long, default_value_t = 5)]
+    liveness_interval_secs: u64,
+    /// Seconds a single liveness check may take before it counts as failed
+    #[clap(long, default_value_t = 5)]
+    liveness_timeout_secs: u64,
+    /// Failed liveness checks in a row after which the browser reports itself unhealthy,
+    /// 0 disables the checks
+    #[clap(long, default_value_t = 3)]
+    liveness_max_failures: usize,
+    /// Reset the browser and go back to the warm pool when the session ends, instead of
+    /// being killed
+    #[clap(long)]
+    recycle: bool,
+    /// Seconds the running session is given to finish on SIGTERM or SIGINT, after the browser
+    /// left the pool and before Chrome and Tzafonwright are stopped. Keep it below the
+    /// termination grace period of the orchestrator
+    #[clap(long, default_value_t = 30)]
+    shutdown_grace_period_secs: u64,
+    /// Directory on a shared volume keeping the named profiles of all tenants, the browser can
+    /// only load and save profiles when set
+    #[clap(long)]
+    saved_profile_dir: Option<PathBuf>,
+    /// JSON file with the launch profile, the profile options below override it
+    #[clap(long)]
+    launch_profile: Option<PathBuf>,
+    #[clap(flatten)]
+    profile: LaunchProfile,
     #[clap(flatten)]
     shared_args: SharedArgs,
 }
 
+/// Chrome and the Tzafonwright connected to it, stopped together
+struct Browser {
+    chrome: ChromeProcess,
+    tzafonwright: JoinHandle<()>,
+    stop_token: CancellationToken,
+    /// Stops Tzafonwright alone, before Chrome is closed under it
+    tzafonwright_stop_token: CancellationToken,
+}
+
+impl Browser {
+    /// Starts with a fresh profile, or a copy of `seed` if set
+    async fn start(
+        args: &Args,
+        seed: Option<&Path>,
+        cancellation_token: &CancellationToken,
+    ) -> anyhow::Result<Self> {
+        let stop_token = cancellation_token.child_token();
+        let tzafonwright_stop_token = stop_token.child_token();
+        let chrome = chrome::start_chrome(
+            &args.chrome_binary_path,
+            &args.profile,
+            seed,
+            stop_token.clone(),
+        )
+        .await?;
+
+        info!("Chrome started, internal ws path: {}", chrome.ws_url);
+        let tzafonwright = tzafonwright::start_tzafonwright(
+            &args.tzafonwright_binary_path,
+            &chrome.ws_url,
+            args.tzafonwright_port,
+            RestartPolicy::new(
+                args.max_tzafonwright_restarts,
+                Duration::from_secs(args.tzafonwright_crash_window_secs),
+            ),
+            Duration::from_secs(args.startup_timeout_secs),
+            tzafonwright_stop_token.clone(),
+            cancellation_token.clone(),
+        )
+        .await?;
+
+        info!("Tzafonwright started");
+        // Registered only once the first client routed here would be served
+        readiness::wait_until_ready(
+            &chrome.ws_url,
+            args.tzafonwright_port,
+            Duration::from_secs(args.startup_timeout_secs),
+        )
+        .await?;
+        Ok(Self {
+            chrome,
+            tzafonwright,
+            stop_token,
+            tzafonwright_stop_token,
+        })
+    }
+
+    /// Points the gateway and the downloads endpoint at this browser
+    fn bind(&self, target: &GatewayTarget, downloads: &DownloadsDir) -> anyhow::Result<()> {
+        let (server_addr, path_override) = gateway_target(&self.chrome.ws_url)?;
+        target.rebind(&server_addr, path_override);
+        downloads.rebind(&self.chrome.launch_dir.downloads_dir());
+        Ok(())
+    }
+
+    /// Kills Chrome and Tzafonwright, waits until both are gone and hands back the launch dir,
+    /// which is wiped once dropped
+    async fn stop(self) -> LaunchDir {
+        self.stop_token.cancel();
+        let _ = self.tzafonwright.await;
+        self.chrome.gone.cancelled().await;
+        self.chrome.launch_dir
+    }
+
+    /// Stops Tzafonwright and lets Chrome close on its own so its profile is complete on disk,
+    /// killing it if it takes too long
+    async fn close(self) -> LaunchDir {
+        self.tzafonwright_stop_token.cancel();
+        if let Err(e) = readiness::close_browser(&self.chrome.ws_url).await {
+            warn!("Failed to close Chrome: {:?}", e);
+        }
+        if tokio::time::timeout(CLOSE_TIMEOUT, self.chrome.gone.cancelled())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Chrome did not close within {:?}, killing it",
+                CLOSE_TIMEOUT
+            );
+        }
+        self.stop().await
+    }
+}
+
+/// Server address and path the gateway forwards to for a DevTools URL
+fn gateway_target(ws_url: &str) -> anyhow::Result<(String, PathOverride)> {
+    let uri = ws_url.parse::<hyper::Uri>()?;
+    let (host, port) = uri
+        .host()
+        .zip(uri.port())
+        .ok_or(anyhow::anyhow!("No host or port"))?;
+    // Other paths are forwarded, so page targets can be reached through the gateway
+    Ok((
+        format!("{}:{}", host, port),
+        PathOverride::ReplaceRoot(uri.path().to_string()),
+    ))
+}
+
+/// Serves the HTTP endpoints of the browser until the container shuts down
+async fn start_http_endpoint(
+    router: axum::Router,
+    port: u16,
+    cancellation_token: &CancellationToken,
+) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
+    let cancellation_token = cancellation_token.clone();
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router)
+            .with_graceful_shutdown(cancellation_token.cancelled_owned())
+            .await
+        {
+            error!("Error serving HTTP endpoint: {:?}", e);
+        }
+    });
+    Ok(())
+}
+
+/// Keeps Chrome and Tzafonwright running behind the registration and the gateway of the browser
+struct Supervisor<'a> {
+    args: &'a Args,
+    instance_id: &'a InstanceId,
+    target: &'a GatewayTarget,
+    downloads: &'a DownloadsDir,
+    channel: &'a Channel,
+    store: Option<ProfileStore>,
+    /// Saved profile the running Chrome was seeded with, locked until it is saved
+    loaded: Option<ProfileKey>,
+    /// Stops the heartbeat, cancelled by the heartbeat itself when the instance is unhealthy
+    heartbeat_token: &'a CancellationToken,
+    /// Keeps the ephemeral proxy from handing out the browser and pauses the liveness check
+    /// while Chrome restarts
+    availability: Availability,
+    /// Stops the CDP gateway, whose handle completes once its connections drained
+    gateway_token: &'a CancellationToken,
+    gateway: JoinHandle<()>,
+    cancellation_token: &'a CancellationToken,
+}
+
+impl Supervisor<'_> {
+    /// Fails if a session holds the browser, it would lose its pages to a restart and the
+    /// client has to get a new browser
+    async fn ensure_detached(&self) -> anyhow::Result<()> {
+        let has_parent = instance_has_parent(
+            &self.args.shared_args.instance_manager_config,
+            self.instance_id,
+        )
+        .await
+        .context("Chrome exited and the parent could not be checked")?;
+        anyhow::ensure!(
+            !has_parent,
+            "Chrome exited while the browser was attached to a session"
+        );
+        Ok(())
+    }
+
+    /// Replaces Chrome and Tzafonwright with new ones, seeded with `seed` if set
+    async fn restart(&self, browser: Browser, seed: Option<&Path>) -> anyhow::Result<Browser> {
+        let _unavailable = self.availability.set_unavailable("Restarting Chrome");
+        browser.stop().await;
+        let browser = Browser::start(self.args, seed, self.cancellation_token).await?;
+        browser.bind(self.target, self.downloads)?;
+        Ok(browser)
+    }
+
+    /// Unlocks the loaded profile without saving it
+    async fn unload(&mut self) {
+        if let Some((store, key)) = self.store.as_ref().zip(self.loaded.take()) {
+            store.unlock(&key).await;
+        }
+    }
+
+    /// Restarts Chrome and Tzafonwright with a fresh profile once the session released the
+    /// browser, checks that nothing of the session is left and hands the browser back to the
+    /// pool
+    async fn recycle(&mut self, browser: Browser) -> anyhow::Result<Browser> {
+        info!("Released by the session, recycling the browser");
+        self.unload().await;
+        let browser = self
+            .restart(browser, None)
+            .await
+            .context("Failed to restart Chrome for recycling")?;
+        readiness::verify_clean(&browser.chrome.ws_url)
+            .await
+            .context("Browser is not clean after recycling")?;
+        shared::utils::detach_instance(self.instance_id, self.channel)
+            .await
+            .context("Failed to go back to the pool")?;
+        info!("Browser recycled");
+        Ok(browser)
+    }
+
+    /// Restarts Chrome seeded with the saved profile, once this browser holds its lock
+    async fn load(
+        &mut self,
+        key: ProfileKey,
+        browser: Browser,
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    ) -> anyhow::Result<Browser> {
+        let locked = match (&self.store, &self.loaded) {
+            (None, _) => Err(anyhow::anyhow!("No profile store")),
+            (Some(_), Some(loaded)) => Err(anyhow::anyhow!("Profile {:?} is loaded", loaded)),
+            (Some(store), None) => store.lock(&key).await,
+        };
+        let Ok(true) = locked else {
+            let _ = reply.send(locked);
+            return Ok(browser);
+        };
+        let seed = self.store.as_ref().and_then(|store| store.saved(&key));
+        info!("Loading profile {:?}", key);
+        // Unlocked when the supervisor stops, even if Chrome fails to restart
+        self.loaded = Some(key);
+        let restarted = self.restart(browser, seed.as_deref()).await;
+        let _ = reply.send(match &restarted {
+            Ok(_) => Ok(true),
+            Err(e) => Err(anyhow::anyhow!("Failed to restart Chrome: {:?}", e)),
+        });
+        restarted
+    }
+
+    /// Closes Chrome, stores its profile in place of the loaded one and unlocks it, then starts
+    /// a fresh Chrome
+    async fn save(
+        &mut self,
+        browser: Browser,
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    ) -> anyhow::Result<Browser> {
+        let Some((store, key)) = self.store.as_ref().zip(self.loaded.take()) else {
+            let _ = reply.send(Ok(()));
+            return Ok(browser);
+        };
+        let _unavailable = self.availability.set_unavailable("Saving the profile");
+        let launch_dir = browser.close().await;
+        let saved = store.save(&key, &launch_dir.user_data_dir()).await;
+        drop(launch_dir);
+        store.unlock(&key).await;
+        let _ = reply.send(saved);
+        let browser = Browser::start(self.args, None, self.cancellation_token)
+            .await
+            .context("Failed to restart Chrome after saving the profile")?;
+        browser.bind(self.target, self.downloads)?;
+        Ok(browser)
+    }
+
+    /// Leaves the pool, gives the running session the grace period to finish and closes Chrome
+    /// and Tzafonwright
+    async fn shut_down(&mut self, browser: Browser, signal: &str) {
+        info!("Received {}, shutting down", signal);
+        // Stopped first, the heartbeat of a killed instance would end the container right away
+        self.heartbeat_token.cancel();
+        if let Err(e) =
+            shared::utils::kill_instance(self.instance_id, KillReason::Shutdown, self.channel).await
+        {
+            error!("Failed to deregister browser: {:?}", e);
+        }
+        self.gateway_token.cancel();
+        if let Err(e) = (&mut self.gateway).await {
+            error!("Gateway failed while draining: {:?}", e);
+        }
+        drop(browser.close().await);
+        info!("Browser closed");
+    }
+
+    async fn handle(
+        &mut self,
+        command: ProfileCommand,
+        browser: Browser,
+    ) -> anyhow::Result<Browser> {
+        match command {
+            ProfileCommand::Load { key, reply } => self.load(key, browser, reply).await,
+            ProfileCommand::Save { reply } => self.save(browser, reply).await,
+        }
+    }
+
+    /// Restarts Chrome and Tzafonwright in place whenever Chrome exits while the browser is
+    /// idle, recycles the browser when its session released it and loads and saves profiles on
+    /// request, keeping the registration and the gateway. Returns once Chrome exited and may
+    /// not be restarted, or the container is shutting down
+    async fn supervise(
+        &mut self,
+        mut browser: Browser,
+        commands: &mut mpsc::Receiver<ProfileCommand>,
+        shutdown_signal: impl Future<Output = &'static str>,
+    ) -> anyhow::Result<()> {
+        let args = self.args;
+        let cancellation_token = self.cancellation_token;
+        let heartbeat_token = self.heartbeat_token;
+        let gateway_token = self.gateway_token;
+        tokio::pin!(shutdown_signal);
+        let mut restart_policy = RestartPolicy::new(
+            args.max_chrome_restarts,
+            Duration::from_secs(args.chrome_crash_window_secs),
+        );
+        let mut release_requested = self.availability.release_requests();
+        loop {
+            let exit = tokio::select! {
+                _ = cancellation_token.cancelled() => return Ok(()),
+                _ = heartbeat_token.cancelled() => anyhow::bail!("Heartbeat stopped"),
+                _ = gateway_token.cancelled() => anyhow::bail!("Gateway stopped"),
+                signal = &mut shutdown_signal => {
+                    self.shut_down(browser, signal).await;
+                    return Ok(());
+                }
+                exit = &mut browser.chrome.exited => exit,
+                Some(command) = commands.recv() => {
+                    browser = self.handle(command, browser).await?;
+                    continue;
+                }
+                Ok(()) = release_requested.changed(), if args.recycle => {
+                    if *release_requested.borrow_and_update() {
+                        browser = self.recycle(browser).await?;
+                    }
+                    continue;
+                }
+            };
+            if !matches!(exit, Ok(Some(_))) {
+                return Ok(());
+            }
+            let Some(backoff) = restart_policy.on_exit(Instant::now()) else {
+                anyhow::bail!(
+                    "Chrome exited more than {} times within {}s",
+                    args.max_chrome_restarts,
+                    args.chrome_crash_window_secs
+                );
+            };
+            let unavailable = self.availability.set_unavailable("Restarting Chrome");
+            self.ensure_detached().await?;
+
+            self.unload().await;
+            info!("Restarting Chrome in {:?}", backoff);
+            tokio::time::sleep(backoff).await;
+            browser = self
+                .restart(browser, None)
+                .await
+                .context("Failed to restart Chrome")?;
+            // A proxy may have attached the browser before the restart reached it
+            self.ensure_detached().await?;
+            drop(unavailable);
+            info!("Chrome restarted in place");
+        }
+    }
+
+    /// Supervises until the browser stops, then gives up the loaded profile
+    async fn run(
+        mut self,
+        browser: Browser,
+        mut commands: mpsc::Receiver<ProfileCommand>,
+        shutdown_signal: impl Future<Output = &'static str>,
+    ) -> anyhow::Result<()> {
+        let result = self
+            .supervise(browser, &mut commands, shutdown_signal)
+            .await;
+        self.unload().await;
+        result
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cancellation_token = CancellationToken::new();
-    let args = Args::try_parse()?;
+    let mut args = Args::try_parse()?;
+    // Listened for from the start, so a signal during startup is not lost
+    let shutdown_signal = shared::utils::shutdown_signal()?;
 
     tracing_subscriber::fmt()
         .with_max_level(if args.shared_args.debug_log {
@@ -62,56 +501,108 @@ async fn main() -> anyhow::Result<()> {
     };
     info!("IP address: {}", ip_address);
 
+    args.profile = std::mem::take(&mut args.profile).load(args.launch_profile.as_deref())?;
     info!("Starting browser container with args: {:?}", args);
-    let ws_path =
-        chrome::start_chrome(&args.chrome_binary_path, cancellation_token.clone()).await?;
-
-    info!("Chrome started, internal ws path: {}", ws_path);
-    tzafonwright::start_tzafonwright(
-        &args.tzafonwright_binary_path,
-        &ws_path,
-        args.tzafonwright_port,
-        cancellation_token.clone(),
-    )
-    .await?;
-
-    info!("Tzafonwright started");
-
-    let uri = hyper::Uri::from_maybe_shared(ws_path)?;
-    let (host, port) = uri
-        .host()
-        .zip(uri.port())
-        .ok_or(anyhow::anyhow!("No host or port"))?;
-    let server_addr = format!("{}:{}", host, port);
+    let browser = Browser::start(&args, None, &cancellation_token).await?;
 
-    let proxy_config = HttpProxyConfig::new(&server_addr)
-        .with_header_override("Host", &server_addr)
-        .with_path_override(PathOverride::Replace(uri.path().to_string()));
+    let (server_addr, path_override) = gateway_target(&browser.chrome.ws_url)?;
+    // Rebound when Chrome restarts on another port
+    let target = GatewayTarget::new(&server_addr, path_override);
+    let proxy_config = HttpProxyConfig::new(&server_addr).with_target(target.clone());
 
     let listen_addr = format!("0.0.0.0:{}", args.cdp_port).parse()?;
 
-    start_simple_http_gateway_with_proxy_config(proxy_config, listen_addr, &cancellation_token)
-        .await
-        .context("Failed to start gateway")?;
+    // Stopped on shutdown before Chrome, so the running session can finish
+    let gateway_token = cancellation_token.child_token();
+    let gateway = start_http_gateway_with_options(
+        proxy_config,
+        listen_addr,
+        GatewayOptions::new()
+            .with_drain_period(Duration::from_secs(args.shutdown_grace_period_secs)),
+        &gateway_token,
+    )
+    .await
+    .context("Failed to start gateway")?;
 
     info!("Proxy started");
+    // Rebound when Chrome restarts with a new launch dir
+    let downloads = DownloadsDir::new(&browser.chrome.launch_dir.downloads_dir());
+    let (commands_sender, commands) = mpsc::channel(1);
+    let mut router = downloads::router(downloads.clone())
+        .merge(live_view::router(target.clone(), &cancellation_token));
+    if args.saved_profile_dir.is_some() {
+        router = router.merge(profile_store::router(commands_sender));
+    }
+    start_http_endpoint(router, args.http_port, &cancellation_token)
+        .await
+        .context("Failed to start HTTP endpoint")?;
     let services = create_services_from_args(
         &ip_address,
         Some(args.cdp_port),
         Some(args.tzafonwright_port),
+        Some(args.http_port),
+        args.saved_profile_dir.as_ref().map(|_| args.http_port),
+        Some(args.http_port),
         None,
     );
-    instance_manager_connection(
+    let liveness = (args.liveness_max_failures > 0).then(|| Liveness {
+        check: Box::new(BrowserLiveness {
+            target: target.clone(),
+            tzafonwright_port: args.tzafonwright_port,
+        }),
+        interval: Duration::from_secs(args.liveness_interval_secs),
+        timeout: Duration::from_secs(args.liveness_timeout_secs),
+        max_failures: args.liveness_max_failures,
+    });
+    let mut labels = args.profile.labels();
+    if args.recycle {
+        labels
+            .labels
+            .insert(RECYCLE_LABEL.to_string(), "true".to_string());
+    }
+    if args.saved_profile_dir.is_some() {
+        labels
+            .labels
+            .insert(SAVED_PROFILES_LABEL.to_string(), "true".to_string());
+    }
+    // Ends the container through the supervisor, except when it was stopped for shutting down
+    let heartbeat_token = cancellation_token.child_token();
+    let availability = instance_manager_connection(
         &args.shared_args.instance_manager_config,
         &instance_id,
         &InstanceType::ChromeBrowser,
         services,
-        &cancellation_token,
+        labels,
+        liveness,
+        &heartbeat_token,
     )
     .await
     .context("Failed to start instance manager connection")?;
 
-    cancellation_token.cancelled().await;
+    let channel = instance_manager::get_channel(&args.shared_args.instance_manager_config)
+        .await
+        .context("Failed to connect to the instance manager")?;
+    let supervisor = Supervisor {
+        args: &args,
+        instance_id: &instance_id,
+        target: &target,
+        downloads: &downloads,
+        channel: &channel,
+        store: args
+            .saved_profile_dir
+            .as_deref()
+            .map(|dir| ProfileStore::new(dir, &instance_id, &channel)),
+        loaded: None,
+        heartbeat_token: &heartbeat_token,
+        availability,
+        gateway_token: &gateway_token,
+        gateway,
+        cancellation_token: &cancellation_token,
+    };
+    if let Err(e) = supervisor.run(browser, commands, shutdown_signal).await {
+        error!("{:?}", e);
+    }
+    cancellation_token.cancel();
     info!("Program exiting");
     Ok(())
 }
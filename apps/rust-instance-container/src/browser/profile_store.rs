@@ -0,0 +1,319 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use axum::Router;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::routing::post;
+use serde::Deserialize;
+use tokio::sync::{mpsc, oneshot};
+use tonic::transport::Channel;
+use tracing::{info, warn};
+
+use instance_container::{
+    LOAD_SAVED_PROFILE_PATH, SAVE_SAVED_PROFILE_PATH, copy_recursively, is_valid_profile_key,
+};
+use shared::add_version;
+use shared::instance_manager::InstanceId;
+use shared::instance_manager::get_service_client::GetServiceClient;
+
+/// Profile directory inside Chrome's user data dir
+const PROFILE_DIR: &str = "Default";
+/// What is kept of a profile between sessions: cookies, local storage and IndexedDB, no caches
+const SAVED_ENTRIES: [&str; 5] = [
+    "Cookies",
+    "Cookies-journal",
+    "Network",
+    "Local Storage",
+    "IndexedDB",
+];
+const LOCK_SUFFIX: &str = ".lock";
+
+/// A saved profile of a tenant
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileKey {
+    pub tenant: String,
+    pub name: String,
+}
+
+impl ProfileKey {
+    /// None unless both parts are valid directory names
+    pub fn new(tenant: &str, name: &str) -> Option<Self> {
+        (is_valid_profile_key(tenant) && is_valid_profile_key(name)).then(|| ProfileKey {
+            tenant: tenant.to_string(),
+            name: name.to_string(),
+        })
+    }
+}
+
+/// Saved profiles of all tenants in a directory on a shared volume, as `<tenant>/<name>`.
+/// A profile is locked by the browser using it with a `<tenant>/<name>.lock` file holding its
+/// instance id, the lock of a browser that is not alive anymore is taken over
+pub struct ProfileStore {
+    dir: PathBuf,
+    instance_id: InstanceId,
+    channel: Channel,
+}
+
+impl ProfileStore {
+    pub fn new(dir: &Path, instance_id: &InstanceId, channel: &Channel) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            instance_id: instance_id.clone(),
+            channel: channel.clone(),
+        }
+    }
+
+    fn profile_dir(&self, key: &ProfileKey) -> PathBuf {
+        self.dir.join(&key.tenant).join(&key.name)
+    }
+
+    fn lock_path(&self, key: &ProfileKey) -> PathBuf {
+        self.dir
+            .join(&key.tenant)
+            .join(format!("{}{}", key.name, LOCK_SUFFIX))
+    }
+
+    /// Whether the browser holding a lock is still registered and not killed
+    async fn is_alive(&self, instance_id: &str) -> anyhow::Result<bool> {
+        let mut client = GetServiceClient::with_interceptor(self.channel.clone(), add_version);
+        match client
+            .get_instance(tonic::Request::new(InstanceId {
+                instance_id: instance_id.to_string(),
+            }))
+            .await
+        {
+            Ok(instance) => Ok(instance.into_inner().kill_instance_request.is_none()),
+            Err(status) if status.code() == tonic::Code::NotFound => Ok(false),
+            Err(e) => Err(anyhow::anyhow!("Error getting lock holder: {}", e)),
+        }
+    }
+
+    /// Next to the lock, for writing or taking over a lock in one step
+    fn temporary_path(&self, key: &ProfileKey) -> PathBuf {
+        self.dir.join(&key.tenant).join(format!(
+            ".{}{}-{}",
+            key.name,
+            LOCK_SUFFIX,
+            uuid::Uuid::new_v4()
+        ))
+    }
+
+    /// Creates the lock with this browser's instance id, false if it exists. The lock is written
+    /// aside and linked in place, so it is never seen empty
+    async fn create_lock(&self, key: &ProfileKey) -> anyhow::Result<bool> {
+        let temporary_path = self.temporary_path(key);
+        tokio::fs::write(&temporary_path, &self.instance_id.instance_id)
+            .await
+            .context("Failed to write lock")?;
+        let linked = tokio::fs::hard_link(&temporary_path, self.lock_path(key)).await;
+        let _ = tokio::fs::remove_file(&temporary_path).await;
+        match linked {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(e) => Err(e).context("Failed to create lock"),
+        }
+    }
+
+    /// Removes the lock if it is still held by `stale_holder`. It is moved aside before it is
+    /// checked, so a lock another browser took over in the meantime is put back instead
+    async fn remove_stale_lock(&self, key: &ProfileKey, stale_holder: &str) -> anyhow::Result<()> {
+        let lock_path = self.lock_path(key);
+        let temporary_path = self.temporary_path(key);
+        match tokio::fs::rename(&lock_path, &temporary_path).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context("Failed to remove stale lock"),
+        }
+        let holder = tokio::fs::read_to_string(&temporary_path)
+            .await
+            .unwrap_or_default();
+        if holder.trim() != stale_holder {
+            match tokio::fs::hard_link(&temporary_path, &lock_path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => warn!("Failed to put back lock of {:?}: {:?}", key, e),
+            }
+        }
+        tokio::fs::remove_file(&temporary_path)
+            .await
+            .context("Failed to remove stale lock")
+    }
+
+    /// Takes the lock of the profile, false while another live browser holds it
+    pub async fn lock(&self, key: &ProfileKey) -> anyhow::Result<bool> {
+        let lock_path = self.lock_path(key);
+        tokio::fs::create_dir_all(self.dir.join(&key.tenant))
+            .await
+            .context("Failed to create tenant dir")?;
+        // Once more after taking over a stale lock
+        for _ in 0..2 {
+            if self.create_lock(key).await? {
+                return Ok(true);
+            }
+            let holder = match tokio::fs::read_to_string(&lock_path).await {
+                Ok(holder) => holder,
+                // Unlocked in the meantime
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context("Failed to read lock"),
+            };
+            let holder = holder.trim();
+            if holder == self.instance_id.instance_id {
+                return Ok(true);
+            }
+            if !holder.is_empty() && self.is_alive(holder).await? {
+                return Ok(false);
+            }
+            warn!("Taking over lock of {:?} from {:?}", key, holder);
+            self.remove_stale_lock(key, holder).await?;
+        }
+        Ok(false)
+    }
+
+    /// Releases the lock of the profile if this browser holds it
+    pub async fn unlock(&self, key: &ProfileKey) {
+        let lock_path = self.lock_path(key);
+        match tokio::fs::read_to_string(&lock_path).await {
+            Ok(holder) if holder.trim() == self.instance_id.instance_id => {
+                if let Err(e) = tokio::fs::remove_file(&lock_path).await {
+                    warn!("Failed to unlock {:?}: {:?}", key, e);
+                }
+            }
+            Ok(holder) => warn!("Lock of {:?} is held by {:?}", key, holder),
+            Err(e) => warn!("Failed to read lock of {:?}: {:?}", key, e),
+        }
+    }
+
+    /// Directory to seed Chrome's profile with, None for a profile that was never saved
+    pub fn saved(&self, key: &ProfileKey) -> Option<PathBuf> {
+        let profile_dir = self.profile_dir(key);
+        profile_dir.is_dir().then_some(profile_dir)
+    }
+
+    /// Replaces the saved profile with the one of a closed Chrome
+    pub async fn save(&self, key: &ProfileKey, user_data_dir: &Path) -> anyhow::Result<()> {
+        let from = user_data_dir.join(PROFILE_DIR);
+        let to = self.profile_dir(key);
+        // Written next to the saved profile first, so a failed save keeps the previous one
+        let staging =
+            self.dir
+                .join(&key.tenant)
+                .join(format!(".{}-{}", key.name, uuid::Uuid::new_v4()));
+        let copied = {
+            let staging = staging.clone();
+            tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+                std::fs::create_dir_all(&staging)?;
+                for entry in SAVED_ENTRIES {
+                    let path = from.join(entry);
+                    if path.exists() {
+                        copy_recursively(&path, &staging.join(entry), None)?;
+                    }
+                }
+                Ok(())
+            })
+            .await?
+        };
+        if let Err(e) = copied {
+            let _ = tokio::fs::remove_dir_all(&staging).await;
+            return Err(e).context("Failed to copy profile");
+        }
+        match tokio::fs::remove_dir_all(&to).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context("Failed to remove previous profile"),
+        }
+        tokio::fs::rename(&staging, &to)
+            .await
+            .context("Failed to store profile")?;
+        info!("Saved profile {:?}", key);
+        Ok(())
+    }
+}
+
+/// Asks the supervisor of the browser to restart Chrome with or without a saved profile
+pub enum ProfileCommand {
+    /// Answers false when another browser uses the profile
+    Load {
+        key: ProfileKey,
+        reply: oneshot::Sender<anyhow::Result<bool>>,
+    },
+    /// Saves and unlocks the loaded profile, if any
+    Save {
+        reply: oneshot::Sender<anyhow::Result<()>>,
+    },
+}
+
+#[derive(Deserialize)]
+struct LoadParams {
+    tenant: String,
+    name: String,
+}
+
+async fn load(
+    State(commands): State<mpsc::Sender<ProfileCommand>>,
+    Query(params): Query<LoadParams>,
+) -> StatusCode {
+    let Some(key) = ProfileKey::new(&params.tenant, &params.name) else {
+        return StatusCode::BAD_REQUEST;
+    };
+    let (reply, answer) = oneshot::channel();
+    if commands
+        .send(ProfileCommand::Load { key, reply })
+        .await
+        .is_err()
+    {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    match answer.await {
+        Ok(Ok(true)) => StatusCode::OK,
+        Ok(Ok(false)) => StatusCode::CONFLICT,
+        Ok(Err(e)) => {
+            warn!("Failed to load profile: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+async fn save(State(commands): State<mpsc::Sender<ProfileCommand>>) -> StatusCode {
+    let (reply, answer) = oneshot::channel();
+    if commands.send(ProfileCommand::Save { reply }).await.is_err() {
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    match answer.await {
+        Ok(Ok(())) => StatusCode::OK,
+        Ok(Err(e)) => {
+            warn!("Failed to save profile: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+/// Loads and saves profiles through the supervisor of the browser
+pub fn router(commands: mpsc::Sender<ProfileCommand>) -> Router {
+    Router::new()
+        .route(LOAD_SAVED_PROFILE_PATH, post(load))
+        .route(SAVE_SAVED_PROFILE_PATH, post(save))
+        .with_state(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_key() {
+        assert_eq!(
+            ProfileKey::new("tenant-1", "shop.example"),
+            Some(ProfileKey {
+                tenant: "tenant-1".to_string(),
+                name: "shop.example".to_string(),
+            })
+        );
+        assert_eq!(ProfileKey::new("tenant-1", ""), None);
+        assert_eq!(ProfileKey::new("tenant-1", ".."), None);
+        assert_eq!(ProfileKey::new("tenant-1", "a/b"), None);
+        assert_eq!(ProfileKey::new("../tenant", "a"), None);
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use serde_json::{Value, json};
+use tokio::io::AsyncRead;
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+use shared::socket_gateway::simple_gateway::{GatewayTarget, PathOverride};
+use shared::socket_gateway::websocket::{
+    OPCODE_PING, OPCODE_PONG, OPCODE_TEXT, WebSocketReader, connect, write_message,
+};
+use shared::utils::{LivenessCheck, LivenessFuture};
+
+/// Time a single probe of Chrome and Tzafonwright may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Sends a CDP command and waits for its result, events received meanwhile are skipped
+pub async fn cdp_command<R: AsyncRead + Unpin>(
+    reader: &mut WebSocketReader<R>,
+    writer: &mut OwnedWriteHalf,
+    id: u64,
+    method: &str,
+    params: Value,
+) -> anyhow::Result<Value> {
+    let command = json!({"id": id, "method": method, "params": params});
+    write_message(writer, OPCODE_TEXT, command.to_string().into_bytes())
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to send {}: {:?}", method, e))?;
+    loop {
+        let (_, payload) = reader
+            .next()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read {} answer: {:?}", method, e))?
+            .ok_or_else(|| anyhow::anyhow!("Chrome closed the connection on {}", method))?;
+        let Ok(mut message) = serde_json::from_slice::<Value>(&payload) else {
+            continue;
+        };
+        if message.get("id").and_then(Value::as_u64) != Some(id) {
+            continue;
+        }
+        if let Some(error) = message.get("error") {
+            anyhow::bail!("{} failed: {}", method, error);
+        }
+        return message
+            .get_mut("result")
+            .map(Value::take)
+            .ok_or_else(|| anyhow::anyhow!("{} answered without a result", method));
+    }
+}
+
+pub async fn open_websocket(
+    addr: &str,
+    path: &str,
+) -> anyhow::Result<(WebSocketReader<OwnedReadHalf>, OwnedWriteHalf)> {
+    let stream = connect(addr, path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to open websocket to {}: {:?}", addr, e))?;
+    let (read_half, write_half) = stream.into_split();
+    Ok((WebSocketReader::new(read_half), write_half))
+}
+
+/// Opens the browser CDP websocket of the DevTools URL
+async fn open_cdp(
+    ws_url: &str,
+) -> anyhow::Result<(WebSocketReader<OwnedReadHalf>, OwnedWriteHalf)> {
+    let uri = ws_url.parse::<hyper::Uri>()?;
+    let addr = uri
+        .authority()
+        .ok_or_else(|| anyhow::anyhow!("No host or port"))?
+        .as_str();
+    open_websocket(addr, uri.path()).await
+}
+
+/// Checks that Chrome answers over CDP and can open a page
+async fn probe_chrome(ws_url: &str) -> anyhow::Result<()> {
+    let (mut reader, mut write_half) = open_cdp(ws_url).await?;
+
+    let version = cdp_command(
+        &mut reader,
+        &mut write_half,
+        1,
+        "Browser.getVersion",
+        json!({}),
+    )
+    .await?;
+    debug!("Chrome version: {}", version);
+    let target = cdp_command(
+        &mut reader,
+        &mut write_half,
+        2,
+        "Target.createTarget",
+        json!({"url": "about:blank"}),
+    )
+    .await?;
+    let target_id = target
+        .get("targetId")
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("Target.createTarget returned no targetId"))?;
+    cdp_command(
+        &mut reader,
+        &mut write_half,
+        3,
+        "Target.closeTarget",
+        json!({"targetId": target_id}),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn probe_tzafonwright(port: u16) -> anyhow::Result<()> {
+    TcpStream::connect(("127.0.0.1", port))
+        .await
+        .map_err(|e| anyhow::anyhow!("Tzafonwright is not listening: {}", e))?;
+    Ok(())
+}
+
+/// Probes Chrome over CDP and the Tzafonwright port until both answer, fails once
+/// `startup_timeout` passed
+pub async fn wait_until_ready(
+    ws_url: &str,
+    tzafonwright_port: u16,
+    startup_timeout: Duration,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    let deadline = started + startup_timeout;
+    loop {
+        let probe = async {
+            probe_chrome(ws_url).await?;
+            probe_tzafonwright(tzafonwright_port).await
+        };
+        let probe_timeout = PROBE_TIMEOUT.min(deadline.saturating_duration_since(Instant::now()));
+        let error = match tokio::time::timeout(probe_timeout, probe).await {
+            Ok(Ok(())) => {
+                info!("Browser ready after {:?}", started.elapsed());
+                return Ok(());
+            }
+            Ok(Err(e)) => e,
+            Err(_) => anyhow::anyhow!("Probe timed out"),
+        };
+        debug!("Browser not ready yet: {:?}", error);
+        if Instant::now() + PROBE_INTERVAL >= deadline {
+            anyhow::bail!(
+                "Browser not ready after {}s: {}",
+                startup_timeout.as_secs(),
+                error
+            );
+        }
+        tokio::time::sleep(PROBE_INTERVAL).await;
+    }
+}
+
+/// Asks Chrome to close, so it writes its profile to disk before exiting
+pub async fn close_browser(ws_url: &str) -> anyhow::Result<()> {
+    let (mut reader, mut write_half) = open_cdp(ws_url).await?;
+    cdp_command(&mut reader, &mut write_half, 1, "Browser.close", json!({})).await?;
+    Ok(())
+}
+
+/// Checks that a browser going back to the pool kept nothing of its previous session: no
+/// cookies and no pages other than blank ones
+pub async fn verify_clean(ws_url: &str) -> anyhow::Result<()> {
+    let (mut reader, mut write_half) = open_cdp(ws_url).await?;
+    let cookies = cdp_command(
+        &mut reader,
+        &mut write_half,
+        1,
+        "Storage.getCookies",
+        json!({}),
+    )
+    .await?;
+    let num_cookies = cookies
+        .get("cookies")
+        .and_then(Value::as_array)
+        .map_or(0, Vec::len);
+    anyhow::ensure!(num_cookies == 0, "{} cookies left", num_cookies);
+    let targets = cdp_command(
+        &mut reader,
+        &mut write_half,
+        2,
+        "Target.getTargets",
+        json!({}),
+    )
+    .await?;
+    let targets = targets
+        .get("targetInfos")
+        .and_then(Value::as_array)
+        .ok_or_else(|| anyhow::anyhow!("Target.getTargets returned no targetInfos"))?;
+    if let Some(url) = targets
+        .iter()
+        .filter(|target| target.get("type").and_then(Value::as_str) == Some("page"))
+        .filter_map(|target| target.get("url").and_then(Value::as_str))
+        .find(|url| *url != "about:blank")
+    {
+        anyhow::bail!("Page {} left open", url);
+    }
+    Ok(())
+}
+
+/// Checks that Chrome still answers `Browser.getVersion` and Tzafonwright a websocket ping
+pub struct BrowserLiveness {
+    /// Follows Chrome when it is restarted
+    pub target: GatewayTarget,
+    pub tzafonwright_port: u16,
+}
+
+impl BrowserLiveness {
+    async fn check_chrome(&self) -> anyhow::Result<()> {
+        let Some((addr, PathOverride::ReplaceRoot(path))) = self.target.get() else {
+            anyhow::bail!("No Chrome to check");
+        };
+        let (mut reader, mut write_half) = open_websocket(&addr, &path).await?;
+        cdp_command(
+            &mut reader,
+            &mut write_half,
+            1,
+            "Browser.getVersion",
+            json!({}),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn check_tzafonwright(&self) -> anyhow::Result<()> {
+        let addr = format!("127.0.0.1:{}", self.tzafonwright_port);
+        let (mut reader, mut write_half) = open_websocket(&addr, "/").await?;
+        write_message(&mut write_half, OPCODE_PING, b"liveness".to_vec())
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to ping Tzafonwright: {:?}", e))?;
+        loop {
+            match reader.next().await {
+                Ok(Some((OPCODE_PONG, _))) => return Ok(()),
+                Ok(Some(_)) => continue,
+                Ok(None) => anyhow::bail!("Tzafonwright closed the connection"),
+                Err(e) => anyhow::bail!("Failed to read Tzafonwright pong: {:?}", e),
+            }
+        }
+    }
+}
+
+impl LivenessCheck for BrowserLiveness {
+    fn check(&self) -> LivenessFuture<'_> {
+        Box::pin(async move {
+            self.check_chrome().await?;
+            self.check_tzafonwright().await
+        })
+    }
+}
@@ -0,0 +1,314 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::Parser;
+use serde_json::Value;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use shared::socket_gateway::recording::{Direction, Record, read_recording};
+use shared::socket_gateway::websocket::{
+    OPCODE_CLOSE, OPCODE_TEXT, WebSocketReader, connect, write_message,
+};
+
+/// Feeds the client side of a recorded session into a browser and prints what the browser
+/// answers, as records in the same format
+#[derive(Parser, Debug)]
+struct Args {
+    /// Recording written by the proxy with `--record-dir`
+    #[clap(long)]
+    recording: PathBuf,
+    /// CDP address of a fresh browser-container
+    #[clap(long, default_value = "127.0.0.1:9222")]
+    target: String,
+    /// Websocket path, defaults to the recorded one
+    #[clap(long)]
+    path: Option<String>,
+    /// Replay speed relative to the recording, 0 sends every message as soon as the answers
+    /// it depended on arrived
+    #[clap(long, default_value_t = 1.0)]
+    speed: f64,
+    /// Seconds to wait for an answer the recorded client had received before sending its
+    /// next message
+    #[clap(long, default_value_t = 30)]
+    answer_timeout_secs: u64,
+}
+
+/// Identifies a server message across the recording and the replay: a response by its
+/// command id, an event by its method and how many of them came before
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum MessageKey {
+    Response(u64),
+    Event(String, usize),
+}
+
+/// Assigns keys to the server messages of one side
+#[derive(Default)]
+struct MessageKeys {
+    event_counts: HashMap<String, usize>,
+}
+
+impl MessageKeys {
+    fn key(&mut self, message: &Value) -> Option<MessageKey> {
+        if let Some(id) = message.get("id").and_then(Value::as_u64) {
+            return Some(MessageKey::Response(id));
+        }
+        let method = message.get("method")?.as_str()?.to_string();
+        let count = self.event_counts.entry(method.clone()).or_default();
+        *count += 1;
+        Some(MessageKey::Event(method, *count))
+    }
+}
+
+/// Server messages received in the replay, notified on every new one
+#[derive(Default)]
+struct Received {
+    messages: Mutex<HashMap<MessageKey, Value>>,
+    notify: Notify,
+}
+
+/// Collects the ids the browser generated in the recording (targets, sessions, frames, ...)
+/// and the ones it generated for the same messages in the replay
+fn map_ids(recorded: &Value, replayed: &Value, ids: &mut HashMap<String, String>) {
+    match (recorded, replayed) {
+        (Value::Object(recorded), Value::Object(replayed)) => {
+            for (key, recorded_value) in recorded {
+                let Some(replayed_value) = replayed.get(key) else {
+                    continue;
+                };
+                if let (Value::String(from), Value::String(to)) = (recorded_value, replayed_value)
+                    && key.ends_with("Id")
+                    && from != to
+                {
+                    ids.insert(from.clone(), to.clone());
+                } else {
+                    map_ids(recorded_value, replayed_value, ids);
+                }
+            }
+        }
+        (Value::Array(recorded), Value::Array(replayed)) => {
+            for (recorded, replayed) in recorded.iter().zip(replayed) {
+                map_ids(recorded, replayed, ids);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Replaces recorded ids in a client message with the ones of the replay
+fn replace_ids(message: &mut Value, ids: &HashMap<String, String>) {
+    match message {
+        Value::String(value) => {
+            if let Some(id) = ids.get(value) {
+                *value = id.clone();
+            }
+        }
+        Value::Object(object) => object.values_mut().for_each(|v| replace_ids(v, ids)),
+        Value::Array(array) => array.iter_mut().for_each(|v| replace_ids(v, ids)),
+        _ => {}
+    }
+}
+
+fn print_record(record: &Record) {
+    if let Ok(line) = serde_json::to_string(record) {
+        println!("{line}");
+    }
+}
+
+/// Waits until the replay received the messages with the given keys, or the timeout passed.
+/// Returns the keys that did not arrive
+async fn wait_for(
+    received: &Received,
+    keys: &HashSet<MessageKey>,
+    timeout: Duration,
+) -> HashSet<MessageKey> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let notified = received.notify.notified();
+        let missing = match received.messages.lock() {
+            Ok(messages) => keys
+                .iter()
+                .filter(|key| !messages.contains_key(key))
+                .cloned()
+                .collect::<HashSet<_>>(),
+            Err(_) => return HashSet::new(),
+        };
+        if missing.is_empty() || tokio::time::timeout_at(deadline, notified).await.is_err() {
+            return missing;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::try_parse()?;
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+
+    let records = read_recording(&args.recording)?;
+    let path = args
+        .path
+        .clone()
+        .or_else(|| {
+            records.iter().find_map(|record| match record {
+                Record::Open { path, .. } => Some(path.clone()),
+                _ => None,
+            })
+        })
+        .unwrap_or_else(|| "/".to_string());
+    info!(
+        "Replaying {} records to {}{}",
+        records.len(),
+        args.target,
+        path
+    );
+    let stream = connect(&args.target, &path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to connect to {}: {:?}", args.target, e))?;
+    let (read_half, mut write_half) = stream.into_split();
+
+    let received = Arc::new(Received::default());
+    let reader = {
+        let received = received.clone();
+        tokio::spawn(async move {
+            let mut reader = WebSocketReader::new(read_half);
+            let mut keys = MessageKeys::default();
+            while let Ok(Some((opcode, payload))) = reader.next().await {
+                if let Ok(message) = serde_json::from_slice::<Value>(&payload)
+                    && let Some(key) = keys.key(&message)
+                    && let Ok(mut messages) = received.messages.lock()
+                {
+                    messages.insert(key, message);
+                }
+                received.notify.notify_waiters();
+                print_record(&Record::message(Direction::ServerToClient, opcode, payload));
+            }
+        })
+    };
+
+    let answer_timeout = Duration::from_secs(args.answer_timeout_secs);
+    let started = Instant::now();
+    let first_timestamp_ms = records.iter().find_map(|record| match record {
+        Record::Message { timestamp_ms, .. } => Some(*timestamp_ms),
+        _ => None,
+    });
+    // Server messages of the recording the client had seen so far
+    let mut recorded = HashMap::new();
+    let mut recorded_keys = MessageKeys::default();
+    let mut ids = HashMap::new();
+    let mut mapped = HashSet::new();
+    for record in records {
+        let Record::Message {
+            timestamp_ms,
+            direction,
+            opcode,
+            text,
+            data,
+        } = record
+        else {
+            continue;
+        };
+        if direction == Direction::ServerToClient {
+            if let Some(message) = text.and_then(|text| serde_json::from_str::<Value>(&text).ok())
+                && let Some(key) = recorded_keys.key(&message)
+            {
+                recorded.insert(key, message);
+            }
+            continue;
+        }
+
+        if args.speed > 0.0
+            && let Some(first_timestamp_ms) = first_timestamp_ms
+        {
+            let offset = timestamp_ms.saturating_sub(first_timestamp_ms) as f64 / args.speed;
+            tokio::time::sleep_until(started + Duration::from_secs_f64(offset / 1000.0)).await;
+        }
+        // Only responses are waited for, events may legitimately differ between runs and
+        // are mapped once they arrive
+        let responses = recorded
+            .keys()
+            .filter(|key| matches!(key, MessageKey::Response(_)) && !mapped.contains(*key))
+            .cloned()
+            .collect::<HashSet<_>>();
+        let missing = wait_for(&received, &responses, answer_timeout).await;
+        if !missing.is_empty() {
+            warn!("Browser did not answer {:?}, continuing", missing);
+        }
+        mapped.extend(missing);
+        if let Ok(messages) = received.messages.lock() {
+            for (key, recorded) in &recorded {
+                if !mapped.contains(key)
+                    && let Some(replayed) = messages.get(key)
+                {
+                    map_ids(recorded, replayed, &mut ids);
+                    mapped.insert(key.clone());
+                }
+            }
+        }
+
+        let payload = match (opcode, text, data) {
+            (OPCODE_TEXT, Some(text), _) => match serde_json::from_str::<Value>(&text) {
+                Ok(mut message) => {
+                    replace_ids(&mut message, &ids);
+                    message.to_string().into_bytes()
+                }
+                Err(_) => text.into_bytes(),
+            },
+            (_, text, data) => data.or(text.map(String::into_bytes)).unwrap_or_default(),
+        };
+        print_record(&Record::message(
+            Direction::ClientToServer,
+            opcode,
+            payload.clone(),
+        ));
+        write_message(&mut write_half, opcode, payload)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to send to browser: {:?}", e))?;
+        if opcode == OPCODE_CLOSE {
+            break;
+        }
+    }
+
+    // Give the browser time to answer the last messages
+    let responses = recorded
+        .keys()
+        .filter(|key| matches!(key, MessageKey::Response(_)) && !mapped.contains(*key))
+        .cloned()
+        .collect::<HashSet<_>>();
+    wait_for(&received, &responses, answer_timeout).await;
+    reader.abort();
+    info!("Replay finished");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_mapping() {
+        let recorded = serde_json::json!({"id": 3, "result": {"targetInfos": [{"targetId": "A", "type": "page"}]}});
+        let replayed = serde_json::json!({"id": 3, "result": {"targetInfos": [{"targetId": "B", "type": "page"}]}});
+        let mut ids = HashMap::new();
+        map_ids(&recorded, &replayed, &mut ids);
+        let mut message = serde_json::json!({"id": 4, "method": "Target.attachToTarget", "params": {"targetId": "A", "flatten": true}});
+        replace_ids(&mut message, &ids);
+        assert_eq!(message["params"]["targetId"], "B");
+
+        let mut keys = MessageKeys::default();
+        let event = serde_json::json!({"method": "Target.targetCreated", "params": {}});
+        assert_eq!(
+            keys.key(&event),
+            Some(MessageKey::Event("Target.targetCreated".to_string(), 1))
+        );
+        assert_eq!(
+            keys.key(&event),
+            Some(MessageKey::Event("Target.targetCreated".to_string(), 2))
+        );
+        assert_eq!(keys.key(&recorded), Some(MessageKey::Response(3)));
+    }
+}
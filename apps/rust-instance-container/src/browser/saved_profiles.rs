@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::info;
+
+use instance_container::{LOAD_SAVED_PROFILE_PATH, SAVE_SAVED_PROFILE_PATH, is_valid_profile_key};
+use shared::socket_gateway::http_proxy::{Error, Request, Response};
+
+/// Named profile a new browser is seeded with, saved back when the session ends
+const SAVED_PROFILE_HEADER: &str = "X-WayPoint-Saved-Profile";
+const SAVED_PROFILE_QUERY_PARAM: &str = "saved_profile";
+/// Profiles of clients without an api key are kept under this tenant
+const DEFAULT_TENANT: &str = "default";
+/// Loading restarts Chrome, saving closes it and copies the profile to the store
+const LOAD_TIMEOUT: Duration = Duration::from_secs(90);
+const SAVE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Takes the name of the saved profile from the `X-WayPoint-Saved-Profile` header or
+/// `saved_profile` query parameter
+pub fn profile_from_request(request: &mut Request) -> Result<Option<String>, Error> {
+    let name = request
+        .header(SAVED_PROFILE_HEADER)
+        .or_else(|| request.query_param(SAVED_PROFILE_QUERY_PARAM))
+        .map(str::to_string);
+    request.remove_header(SAVED_PROFILE_HEADER);
+    request.remove_query_param(SAVED_PROFILE_QUERY_PARAM);
+    match name {
+        Some(name) if !is_valid_profile_key(&name) => {
+            Err(Error::ParseError("Invalid saved profile name"))
+        }
+        name => Ok(name),
+    }
+}
+
+/// Sends an empty POST to the saved profile service of a browser, returns the status code
+async fn post(address: &str, path: &str, timeout: Duration) -> Result<u16, Error> {
+    let exchange = async {
+        let mut stream = tokio::net::TcpStream::connect(address).await?;
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            path, address
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| Error::Timeout("Saved profile service did not answer"))?
+        .map_err(|_| Error::IoError("Failed to reach saved profile service"))?;
+    Response::new(&String::from_utf8_lossy(&response))
+        .ok()
+        .and_then(|response| response.status_code())
+        .ok_or(Error::IoError("Invalid answer of saved profile service"))
+}
+
+/// Restarts the browser seeded with the saved profile of the tenant, a profile that was never
+/// saved starts empty
+pub async fn load(address: &str, tenant_id: Option<&str>, name: &str) -> Result<(), Error> {
+    let tenant = tenant_id.unwrap_or(DEFAULT_TENANT);
+    if !is_valid_profile_key(tenant) {
+        return Err(Error::ParseError("Tenant can't have saved profiles"));
+    }
+    info!(
+        "Loading saved profile {} of tenant {} on {}",
+        name, tenant, address
+    );
+    let path = format!(
+        "{}?tenant={}&name={}",
+        LOAD_SAVED_PROFILE_PATH, tenant, name
+    );
+    match post(address, &path, LOAD_TIMEOUT).await? {
+        200 => Ok(()),
+        409 => Err(Error::Conflict("Saved profile is in use")),
+        _ => Err(Error::IoError("Failed to load saved profile")),
+    }
+}
+
+/// Stores the profile the browser was loaded with back in the profile store
+pub async fn save(address: &str) -> Result<(), Error> {
+    match post(address, SAVE_SAVED_PROFILE_PATH, SAVE_TIMEOUT).await? {
+        200 => Ok(()),
+        _ => Err(Error::IoError("Failed to save saved profile")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_profile_from_request() {
+        let mut request =
+            Request::new("GET / HTTP/1.1\r\nX-WayPoint-Saved-Profile: shop\r\n\r\n").unwrap();
+        assert_eq!(
+            profile_from_request(&mut request).unwrap(),
+            Some("shop".to_string())
+        );
+        assert_eq!(request.header(SAVED_PROFILE_HEADER), None);
+
+        let mut request = Request::new("GET /?saved_profile=shop HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(
+            profile_from_request(&mut request).unwrap(),
+            Some("shop".to_string())
+        );
+        assert_eq!(request.query_param(SAVED_PROFILE_QUERY_PARAM), None);
+
+        let mut request = Request::new("GET /?saved_profile=.. HTTP/1.1\r\n\r\n").unwrap();
+        assert!(profile_from_request(&mut request).is_err());
+
+        let mut request = Request::new("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(profile_from_request(&mut request).unwrap(), None);
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::Channel;
+use tracing::{error, info, warn};
+
+use shared::instance_manager::{InstanceId, KillReason, Services};
+use shared::socket_gateway::http_proxy::{Error, Request};
+
+use crate::tenants::BrowserSlot;
+
+/// Response header the session token is handed out in, and request header to resume it with
+pub const SESSION_HEADER: &str = "X-WayPoint-Session";
+const SESSION_QUERY_PARAM: &str = "session";
+/// Routes a connection to a browser the caller holds the session token of:
+/// `/session/<instance_id>/<path>`
+const SESSION_ROUTE_PREFIX: &str = "/session/";
+const REAPER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Splits a `/session/<instance_id>/<path>` route into the instance id and the path to forward
+pub fn parse_session_route(path: &str) -> Option<(String, String)> {
+    let route = path.strip_prefix(SESSION_ROUTE_PREFIX)?;
+    let split = route.find(['/', '?']).unwrap_or(route.len());
+    let (instance_id, rest) = route.split_at(split);
+    if instance_id.is_empty() {
+        return None;
+    }
+    let rest = if rest.starts_with('/') {
+        rest.to_string()
+    } else {
+        format!("/{}", rest)
+    };
+    Some((instance_id.to_string(), rest))
+}
+
+struct Session {
+    /// Identifies the session in logs and to the browser, unlike the token it grants no access
+    id: String,
+    instance_id: String,
+    services: Services,
+    tenant_id: Option<String>,
+    connections: usize,
+    disconnected_at: Option<Instant>,
+    /// Keeps the browser counted against the tenant until the session ends
+    browser_slot: Option<BrowserSlot>,
+    /// The browser resets itself and goes back to the pool when the session ends normally
+    recyclable: bool,
+    /// The browser was loaded with a saved profile, stored back when the session ends normally
+    saves_profile: bool,
+}
+
+/// A connection's view of its session
+pub struct SessionRef {
+    pub token: String,
+    pub id: String,
+    pub instance_id: String,
+    pub services: Services,
+}
+
+impl SessionRef {
+    fn new(token: &str, session: &Session) -> Self {
+        SessionRef {
+            token: token.to_string(),
+            id: session.id.clone(),
+            instance_id: session.instance_id.clone(),
+            services: session.services.clone(),
+        }
+    }
+}
+
+/// Browsers handed out by the proxy, kept reserved for `grace_period` after their last
+/// connection closed so a client can reconnect to the same browser.
+/// A session holds all services of its browser, so it can be resumed on any of the proxy ports
+#[derive(Clone)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    grace_period: Duration,
+    /// The proxy, parent of the browsers
+    instance_id: InstanceId,
+    channel: Channel,
+}
+
+impl Sessions {
+    pub fn new(grace_period: Duration, instance_id: InstanceId, channel: Channel) -> Self {
+        Sessions {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            grace_period,
+            instance_id,
+            channel,
+        }
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Session>>, Error> {
+        self.sessions
+            .lock()
+            .map_err(|_| Error::IoError("Sessions lock poisoned"))
+    }
+
+    /// Takes the session token from the `X-WayPoint-Session` header or `session` query parameter
+    pub fn token_from_request(request: &mut Request) -> Option<String> {
+        let token = request
+            .header(SESSION_HEADER)
+            .or_else(|| request.query_param(SESSION_QUERY_PARAM))
+            .map(str::to_string);
+        request.remove_header(SESSION_HEADER);
+        request.remove_query_param(SESSION_QUERY_PARAM);
+        token
+    }
+
+    /// Registers a newly allocated browser under a new session
+    pub fn create(
+        &self,
+        instance_id: String,
+        services: Services,
+        tenant_id: Option<String>,
+        browser_slot: Option<BrowserSlot>,
+        recyclable: bool,
+        saves_profile: bool,
+    ) -> Result<SessionRef, Error> {
+        let token = uuid::Uuid::new_v4().to_string();
+        let session = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            instance_id,
+            services,
+            tenant_id,
+            connections: 1,
+            disconnected_at: None,
+            browser_slot,
+            recyclable,
+            saves_profile,
+        };
+        let session_ref = SessionRef::new(&token, &session);
+        self.lock()?.insert(token, session);
+        Ok(session_ref)
+    }
+
+    /// Attaches a new connection to an existing session
+    pub fn resume(&self, token: &str, tenant_id: Option<&str>) -> Result<SessionRef, Error> {
+        let mut lock = self.lock()?;
+        let session = lock
+            .get_mut(token)
+            .filter(|session| session.tenant_id.as_deref() == tenant_id)
+            .ok_or(Error::NotFound("Unknown or expired session"))?;
+        session.connections += 1;
+        session.disconnected_at = None;
+        info!(
+            "Resumed session {} on instance: {}",
+            session.id, session.instance_id
+        );
+        Ok(SessionRef::new(token, session))
+    }
+
+    /// Detaches a connection, the browser is killed once the grace period passed without
+    /// a reconnect
+    pub async fn release(&self, token: &str) -> Result<(), Error> {
+        let expired = {
+            let mut lock = self.lock()?;
+            let Some(session) = lock.get_mut(token) else {
+                return Ok(());
+            };
+            session.connections = session.connections.saturating_sub(1);
+            if session.connections > 0 {
+                return Ok(());
+            }
+            if self.grace_period.is_zero() {
+                lock.remove(token)
+            } else {
+                session.disconnected_at = Some(Instant::now());
+                None
+            }
+        };
+        if let Some(session) = expired {
+            self.kill(session, KillReason::Killed).await?;
+        }
+        Ok(())
+    }
+
+    /// Ends a session whose browser turned out to be broken right away, its browser slot is
+    /// returned to allocate another browser with
+    pub async fn discard(
+        &self,
+        token: &str,
+        kill_reason: KillReason,
+    ) -> Result<Option<BrowserSlot>, Error> {
+        let Some(mut session) = self.lock()?.remove(token) else {
+            return Ok(None);
+        };
+        let browser_slot = session.browser_slot.take();
+        self.kill(session, kill_reason).await?;
+        Ok(browser_slot)
+    }
+
+    async fn kill(&self, session: Session, kill_reason: KillReason) -> Result<(), Error> {
+        if session.saves_profile
+            && kill_reason == KillReason::Killed
+            && let Some(address) = &session.services.saved_profile_service
+            && let Err(e) = crate::saved_profiles::save(address).await
+        {
+            warn!(
+                "Failed to save the profile of session {}: {:?}",
+                session.id, e
+            );
+        }
+        let instance_id = InstanceId {
+            instance_id: session.instance_id,
+        };
+        if session.recyclable && kill_reason == KillReason::Killed {
+            info!(
+                "Ending session {} on instance: {} by releasing it",
+                session.id, instance_id.instance_id
+            );
+            match shared::utils::release_instance(&instance_id, &self.instance_id, &self.channel)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!(
+                    "Failed to release instance: {}, killing it: {:?}",
+                    instance_id.instance_id, e
+                ),
+            }
+        }
+        info!(
+            "Ending session {} on instance: {} reason: {:?}",
+            session.id, instance_id.instance_id, kill_reason
+        );
+        shared::utils::kill_instance(&instance_id, kill_reason, &self.channel)
+            .await
+            .map_err(|_| Error::IoError("Failed to kill instance"))
+    }
+
+    fn take_expired(&self) -> Vec<Session> {
+        let Ok(mut lock) = self.sessions.lock() else {
+            return Vec::new();
+        };
+        let expired = lock
+            .iter()
+            .filter(|(_, session)| {
+                session
+                    .disconnected_at
+                    .is_some_and(|t| t.elapsed() >= self.grace_period)
+            })
+            .map(|(token, _)| token.clone())
+            .collect::<Vec<_>>();
+        expired
+            .iter()
+            .filter_map(|token| lock.remove(token))
+            .collect()
+    }
+
+    /// Kills browsers whose grace period passed
+    pub fn start_reaper(&self, cancellation_token: &CancellationToken) {
+        let sessions = self.clone();
+        let cancellation_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            loop {
+                for session in sessions.take_expired() {
+                    if let Err(e) = sessions.kill(session, KillReason::Killed).await {
+                        error!("Failed to end session: {:?}", e);
+                    }
+                }
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => break,
+                    _ = tokio::time::sleep(REAPER_INTERVAL) => {}
+                }
+            }
+        });
+    }
+
+    /// Kills the browsers of all remaining sessions, used on shutdown
+    pub async fn end_all(&self) {
+        let sessions = match self.sessions.lock() {
+            Ok(mut lock) => lock.drain().map(|(_, session)| session).collect::<Vec<_>>(),
+            Err(_) => return,
+        };
+        for session in sessions {
+            if let Err(e) = self.kill(session, KillReason::Killed).await {
+                error!("Failed to end session: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_session_route() {
+        assert_eq!(
+            parse_session_route("/session/browser-1/devtools/page/A"),
+            Some(("browser-1".to_string(), "/devtools/page/A".to_string()))
+        );
+        assert_eq!(
+            parse_session_route("/session/browser-1"),
+            Some(("browser-1".to_string(), "/".to_string()))
+        );
+        assert_eq!(
+            parse_session_route("/session/browser-1?a=b"),
+            Some(("browser-1".to_string(), "/?a=b".to_string()))
+        );
+        assert_eq!(parse_session_route("/session/"), None);
+        assert_eq!(parse_session_route("/devtools/browser"), None);
+    }
+}
@@ -0,0 +1,290 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::time::Instant;
+use tracing::warn;
+
+use shared::socket_gateway::bandwidth::{RateLimit, SharedRateLimiter};
+use shared::socket_gateway::http_proxy::{Error, Request};
+
+const API_KEY_HEADER: &str = "X-Api-Key";
+const API_KEY_QUERY_PARAM: &str = "api_key";
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct TenantLimits {
+    pub max_concurrent_browsers: usize,
+    pub max_connections_per_minute: usize,
+    /// Bandwidth in each direction shared by all connections of the tenant
+    pub max_bytes_per_second: Option<u64>,
+}
+
+#[derive(Default)]
+struct TenantUsage {
+    active_browsers: usize,
+    recent_connections: VecDeque<Instant>,
+    rate_limiter: Option<SharedRateLimiter>,
+}
+
+struct InnerTenants {
+    /// api key -> tenant id
+    api_keys: HashMap<String, String>,
+    limits: HashMap<String, TenantLimits>,
+    usage: HashMap<String, TenantUsage>,
+}
+
+/// Api keys and usage of every tenant allowed to use the proxy
+#[derive(Clone)]
+pub struct Tenants(Arc<Mutex<InnerTenants>>);
+
+/// Holds one of the concurrent browsers of a tenant, released on drop
+pub struct BrowserSlot {
+    tenants: Tenants,
+    tenant_id: String,
+}
+
+impl Drop for BrowserSlot {
+    fn drop(&mut self) {
+        if let Ok(mut lock) = self.tenants.0.lock()
+            && let Some(usage) = lock.usage.get_mut(&self.tenant_id)
+        {
+            usage.active_browsers = usage.active_browsers.saturating_sub(1);
+        }
+    }
+}
+
+/// Parses a key file, one tenant key per line:
+/// `<tenant_id> <api_key> [max_concurrent_browsers] [max_connections_per_minute] [max_bytes_per_second]`
+/// Empty lines and lines starting with `#` are ignored
+fn parse_key_file(
+    content: &str,
+    default_limits: TenantLimits,
+) -> anyhow::Result<(HashMap<String, String>, HashMap<String, TenantLimits>)> {
+    let mut api_keys = HashMap::new();
+    let mut limits = HashMap::new();
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let (tenant_id, api_key, limit_fields) = match fields.as_slice() {
+            [tenant_id, api_key, limit_fields @ ..] if limit_fields.len() <= 3 => {
+                (tenant_id, api_key, limit_fields)
+            }
+            _ => anyhow::bail!("Malformed key file line {}", line_number + 1),
+        };
+        let max_concurrent_browsers = limit_fields.first();
+        let max_connections_per_minute = limit_fields.get(1);
+        let max_bytes_per_second = limit_fields.get(2);
+        let tenant_limits = TenantLimits {
+            max_concurrent_browsers: max_concurrent_browsers
+                .map(|v| v.parse())
+                .transpose()
+                .with_context(|| format!("Invalid browser limit on line {}", line_number + 1))?
+                .unwrap_or(default_limits.max_concurrent_browsers),
+            max_connections_per_minute: max_connections_per_minute
+                .map(|v| v.parse())
+                .transpose()
+                .with_context(|| format!("Invalid rate limit on line {}", line_number + 1))?
+                .unwrap_or(default_limits.max_connections_per_minute),
+            max_bytes_per_second: max_bytes_per_second
+                .map(|v| v.parse())
+                .transpose()
+                .with_context(|| format!("Invalid bandwidth limit on line {}", line_number + 1))?
+                .or(default_limits.max_bytes_per_second),
+        };
+        if api_keys
+            .insert(api_key.to_string(), tenant_id.to_string())
+            .is_some()
+        {
+            anyhow::bail!("Duplicate api key on line {}", line_number + 1);
+        }
+        limits.insert(tenant_id.to_string(), tenant_limits);
+    }
+    Ok((api_keys, limits))
+}
+
+impl Tenants {
+    pub fn load(path: &Path, default_limits: TenantLimits) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path).context("Failed to read api key file")?;
+        let (api_keys, limits) = parse_key_file(&content, default_limits)?;
+        Ok(Tenants(Arc::new(Mutex::new(InnerTenants {
+            api_keys,
+            limits,
+            usage: HashMap::new(),
+        }))))
+    }
+
+    /// Resolves the tenant from the api key of the request, taken from the `X-Api-Key` or
+    /// `Authorization: Bearer` header or the `api_key` query parameter.
+    /// The key is removed from the request so it is never forwarded to the browser
+    pub fn authenticate(&self, request: &mut Request) -> Result<String, Error> {
+        let api_key = request
+            .header(API_KEY_HEADER)
+            .or_else(|| {
+                request
+                    .header("Authorization")
+                    .and_then(|v| v.strip_prefix("Bearer "))
+            })
+            .or_else(|| request.query_param(API_KEY_QUERY_PARAM))
+            .map(|v| v.trim().to_string())
+            .ok_or(Error::Unauthorized("Missing api key"))?;
+        request.remove_header(API_KEY_HEADER);
+        request.remove_header("Authorization");
+        request.remove_query_param(API_KEY_QUERY_PARAM);
+
+        let lock = self
+            .0
+            .lock()
+            .map_err(|_| Error::IoError("Tenants lock poisoned"))?;
+        lock.api_keys
+            .get(&api_key)
+            .cloned()
+            .ok_or(Error::Unauthorized("Invalid api key"))
+    }
+
+    /// Rate limiter shared by the connections of the tenant, None if its bandwidth is unlimited
+    pub fn rate_limiter(&self, tenant_id: &str) -> Result<Option<SharedRateLimiter>, Error> {
+        let mut lock = self
+            .0
+            .lock()
+            .map_err(|_| Error::IoError("Tenants lock poisoned"))?;
+        let Some(max_bytes_per_second) = lock
+            .limits
+            .get(tenant_id)
+            .ok_or(Error::Unauthorized("Unknown tenant"))?
+            .max_bytes_per_second
+        else {
+            return Ok(None);
+        };
+        let usage = lock.usage.entry(tenant_id.to_string()).or_default();
+        let rate_limiter = usage.rate_limiter.get_or_insert_with(|| {
+            let limit = RateLimit::per_second(max_bytes_per_second);
+            SharedRateLimiter::new(Some(limit), Some(limit))
+        });
+        Ok(Some(rate_limiter.clone()))
+    }
+
+    /// Counts a new connection of the tenant, refused once it exceeds its connection rate
+    pub fn check_connection_rate(&self, tenant_id: &str) -> Result<(), Error> {
+        let mut lock = self
+            .0
+            .lock()
+            .map_err(|_| Error::IoError("Tenants lock poisoned"))?;
+        let limits = *lock
+            .limits
+            .get(tenant_id)
+            .ok_or(Error::Unauthorized("Unknown tenant"))?;
+        let usage = lock.usage.entry(tenant_id.to_string()).or_default();
+
+        let now = Instant::now();
+        while usage
+            .recent_connections
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > RATE_WINDOW)
+        {
+            usage.recent_connections.pop_front();
+        }
+        if usage.recent_connections.len() >= limits.max_connections_per_minute {
+            warn!("Tenant {} exceeded its connection rate", tenant_id);
+            return Err(Error::TooManyRequests("Connection rate limit exceeded"));
+        }
+        usage.recent_connections.push_back(now);
+        Ok(())
+    }
+
+    /// Reserves a browser for the tenant if it is within its concurrent browser limit
+    pub fn acquire(&self, tenant_id: &str) -> Result<BrowserSlot, Error> {
+        let mut lock = self
+            .0
+            .lock()
+            .map_err(|_| Error::IoError("Tenants lock poisoned"))?;
+        let limits = *lock
+            .limits
+            .get(tenant_id)
+            .ok_or(Error::Unauthorized("Unknown tenant"))?;
+        let usage = lock.usage.entry(tenant_id.to_string()).or_default();
+        if usage.active_browsers >= limits.max_concurrent_browsers {
+            warn!("Tenant {} exceeded its concurrent browsers", tenant_id);
+            return Err(Error::TooManyRequests("Concurrent browser limit exceeded"));
+        }
+        usage.active_browsers += 1;
+        Ok(BrowserSlot {
+            tenants: self.clone(),
+            tenant_id: tenant_id.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT_LIMITS: TenantLimits = TenantLimits {
+        max_concurrent_browsers: 2,
+        max_connections_per_minute: 10,
+        max_bytes_per_second: None,
+    };
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_parse_key_file() {
+        let content = "# comment\n\nacme key-1\nglobex key-2 5 100\ninitech key-3 1 10 4096\n";
+        let (api_keys, limits) = parse_key_file(content, DEFAULT_LIMITS).unwrap();
+        assert_eq!(api_keys.get("key-1").map(String::as_str), Some("acme"));
+        assert_eq!(api_keys.get("key-2").map(String::as_str), Some("globex"));
+        assert_eq!(limits["acme"].max_concurrent_browsers, 2);
+        assert_eq!(limits["globex"].max_concurrent_browsers, 5);
+        assert_eq!(limits["globex"].max_connections_per_minute, 100);
+        assert_eq!(limits["globex"].max_bytes_per_second, None);
+        assert_eq!(limits["initech"].max_bytes_per_second, Some(4096));
+        assert!(parse_key_file("acme", DEFAULT_LIMITS).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_concurrent_browser_limit() {
+        let (api_keys, limits) = parse_key_file("acme key-1", DEFAULT_LIMITS).unwrap();
+        let tenants = Tenants(Arc::new(Mutex::new(InnerTenants {
+            api_keys,
+            limits,
+            usage: HashMap::new(),
+        })));
+        let first = tenants.acquire("acme");
+        let second = tenants.acquire("acme");
+        assert!(first.is_ok() && second.is_ok());
+        assert!(matches!(
+            tenants.acquire("acme"),
+            Err(Error::TooManyRequests(_))
+        ));
+        drop(first);
+        assert!(tenants.acquire("acme").is_ok());
+        assert!(matches!(
+            tenants.acquire("other"),
+            Err(Error::Unauthorized(_))
+        ));
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_connection_rate_limit() {
+        let (api_keys, limits) = parse_key_file("acme key-1 1 2", DEFAULT_LIMITS).unwrap();
+        let tenants = Tenants(Arc::new(Mutex::new(InnerTenants {
+            api_keys,
+            limits,
+            usage: HashMap::new(),
+        })));
+        assert!(tenants.check_connection_rate("acme").is_ok());
+        assert!(tenants.check_connection_rate("acme").is_ok());
+        assert!(matches!(
+            tenants.check_connection_rate("acme"),
+            Err(Error::TooManyRequests(_))
+        ));
+        // Browsers are limited separately from connections
+        assert!(tenants.acquire("acme").is_ok());
+    }
+}
@@ -1,62 +1,58 @@
-use std::{path::Path, process::Stdio};
+use std::path::Path;
+use std::time::Duration;
 
+use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info};
+use tracing::error;
 
-use instance_container::spawn_pipe_monitor;
+use instance_container::child_process::{ChildSpec, Readiness, RestartPolicy, start_child};
 
-/// Launches a headless Tzafonwright instance with the provided parameters
-/// Starts the process and sets up monitoring tasks for stdout/stderr
+/// Launches a headless Tzafonwright instance with the provided parameters, returns once it
+/// listens on `port`. It is restarted as `restart` allows when it exits on its own, and killed
+/// when `stop_token` is cancelled. Once it may not be restarted anymore `cancellation_token` is
+/// cancelled. The returned task finishes once it is gone
 pub async fn start_tzafonwright(
     tzafonwright_folder: &Path,
     cdp_url: &str,
     port: u16,
+    restart: RestartPolicy,
+    startup_timeout: Duration,
+    stop_token: CancellationToken,
     cancellation_token: CancellationToken,
-) -> anyhow::Result<()> {
-    let mut tzafonwright = tokio::process::Command::new("uv");
-    // Set working directory
-    tzafonwright
-        .current_dir(tzafonwright_folder)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .stdin(Stdio::null());
-    // Ensure Tzafonw process is terminated when this process exits
-    tzafonwright.kill_on_drop(true);
-
-    tzafonwright
-        .arg("run")
-        .arg("src/tzafonwright/server.py")
-        .arg("--port")
-        .arg(port.to_string())
-        .arg("--cdp-url")
-        .arg(cdp_url);
-
-    info!("Starting Tzafonwright {:?}", tzafonwright);
-    let mut child = tzafonwright.spawn()?;
-    let stdout = child
-        .stdout
-        .take()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get stdout"))?;
-    let stderr = child
-        .stderr
-        .take()
-        .ok_or_else(|| anyhow::anyhow!("Failed to get stderr"))?;
-
-    tokio::spawn(async move {
-        tokio::select! {
-            _ = cancellation_token.cancelled() => {
-                let res = child.kill().await;
-                error!("Tzafonwright killed: {res:?}");
-            }
-            res = child.wait() => {
-                error!("Tzafonwright exited: {res:?}");
-                cancellation_token.cancel();
-            }
+) -> anyhow::Result<JoinHandle<()>> {
+    let tzafonwright_folder = tzafonwright_folder.to_path_buf();
+    let cdp_url = cdp_url.to_string();
+    let command = move || {
+        let mut tzafonwright = tokio::process::Command::new("uv");
+        tzafonwright
+            .current_dir(&tzafonwright_folder)
+            .arg("run")
+            .arg("src/tzafonwright/server.py")
+            .arg("--port")
+            .arg(port.to_string())
+            .arg("--cdp-url")
+            .arg(&cdp_url);
+        tzafonwright
+    };
+    let child = start_child(
+        ChildSpec {
+            name: "tzafonwright".to_string(),
+            command: Box::new(command),
+            readiness: Some(Readiness {
+                port,
+                timeout: startup_timeout,
+            }),
+            restart: Some(restart),
+            on_stderr_line: None,
+        },
+        stop_token,
+    )
+    .await?;
+
+    Ok(tokio::spawn(async move {
+        if let Ok(Some(exit)) = child.exited.await {
+            error!("Tzafonwright keeps exiting, last exit: {exit:?}");
+            cancellation_token.cancel();
         }
-    });
-
-    spawn_pipe_monitor(stderr, "Tzafonwright stderr");
-    spawn_pipe_monitor(stdout, "Tzafonwright stdout");
-
-    Ok(())
+    }))
 }
@@ -0,0 +1,550 @@
+use std::collections::{HashMap, VecDeque};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{Level, debug, error, info, warn};
+
+const READINESS_POLL_INTERVAL: Duration = Duration::from_millis(250);
+const RESOURCE_SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Units of the cpu times and resident set size in `/proc/<pid>/stat` when `getconf` can't tell
+const DEFAULT_CLOCK_TICKS_PER_SECOND: u64 = 100;
+const DEFAULT_PAGE_SIZE: u64 = 4096;
+
+/// Allows a restart unless the child already crashed `max_crashes` times within `window`
+#[derive(Debug)]
+pub struct CrashWindow {
+    max_crashes: usize,
+    window: Duration,
+    crashes: VecDeque<Instant>,
+}
+
+impl CrashWindow {
+    pub fn new(max_crashes: usize, window: Duration) -> Self {
+        Self {
+            max_crashes,
+            window,
+            crashes: VecDeque::new(),
+        }
+    }
+
+    /// Records a crash, returns whether the child may be restarted
+    pub fn record_crash(&mut self, now: Instant) -> bool {
+        while self
+            .crashes
+            .front()
+            .is_some_and(|crash| now.duration_since(*crash) >= self.window)
+        {
+            self.crashes.pop_front();
+        }
+        self.crashes.push_back(now);
+        self.crashes.len() <= self.max_crashes
+    }
+}
+
+/// Restarts a child that exited on its own at most `max_restarts` times within `window`,
+/// waiting twice as long after every exit still in the window
+#[derive(Debug)]
+pub struct RestartPolicy {
+    crash_window: CrashWindow,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RestartPolicy {
+    pub fn new(max_restarts: usize, window: Duration) -> Self {
+        Self {
+            crash_window: CrashWindow::new(max_restarts, window),
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    pub fn with_backoff(mut self, initial_backoff: Duration, max_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Records an exit, returns how long to wait before the restart, None once the child may
+    /// not be restarted anymore
+    pub fn on_exit(&mut self, now: Instant) -> Option<Duration> {
+        if !self.crash_window.record_crash(now) {
+            return None;
+        }
+        let exits = self.crash_window.crashes.len().saturating_sub(1) as u32;
+        Some(
+            self.initial_backoff
+                .saturating_mul(2u32.saturating_pow(exits))
+                .min(self.max_backoff),
+        )
+    }
+}
+
+/// The child is ready once it accepts connections on a local port
+#[derive(Debug, Clone, Copy)]
+pub struct Readiness {
+    pub port: u16,
+    pub timeout: Duration,
+}
+
+pub type LineHook = Box<dyn Fn(&str) + Send + Sync>;
+
+/// How to run a child process
+pub struct ChildSpec {
+    /// Name the logs and resource usage of the child are reported under
+    pub name: String,
+    /// Creates the command anew for every start, its output is piped into the logs
+    pub command: Box<dyn Fn() -> Command + Send + Sync>,
+    /// Probed after every start, a child not ready in time failed to start
+    pub readiness: Option<Readiness>,
+    /// Without one the first exit is reported
+    pub restart: Option<RestartPolicy>,
+    /// Sees every line the child writes to stderr, e.g. to pick up the address it listens on
+    pub on_stderr_line: Option<LineHook>,
+}
+
+/// CPU and memory of a child and all its descendants
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub cpu_seconds: f64,
+    pub rss_bytes: u64,
+    pub processes: usize,
+}
+
+/// A child started by [`start_child`], its resource usage is logged periodically
+pub struct SupervisedChild {
+    /// Resolves once the child is gone for good: with its last exit once it exited and may not
+    /// be restarted, or None once it was stopped
+    pub exited: JoinHandle<Option<std::io::Result<ExitStatus>>>,
+}
+
+/// Level of a log line: Chrome's `[pid:tid:time:LEVEL:file(line)]` prefix or Python logging's
+/// `LEVEL:` prefix, None for other lines
+fn parse_level(line: &str) -> Option<Level> {
+    let name = match line.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        Some((prefix, _)) => prefix.split(':').nth(3)?,
+        None => line.split_once(':')?.0.trim(),
+    };
+    match name {
+        "FATAL" | "CRITICAL" | "ERROR" => Some(Level::ERROR),
+        "WARNING" | "WARN" => Some(Level::WARN),
+        "INFO" => Some(Level::INFO),
+        "DEBUG" => Some(Level::DEBUG),
+        name if name.starts_with("VERBOSE") => Some(Level::DEBUG),
+        _ => None,
+    }
+}
+
+fn log_line(child: &str, stream: &str, line: &str) {
+    match parse_level(line).unwrap_or(Level::INFO) {
+        Level::ERROR => error!(child, stream, "{}", line),
+        Level::WARN => warn!(child, stream, "{}", line),
+        Level::INFO => info!(child, stream, "{}", line),
+        _ => debug!(child, stream, "{}", line),
+    }
+}
+
+/// Logs the lines of a pipe of the child at the level they carry
+fn forward_logs(
+    pipe: impl AsyncRead + Unpin + Send + 'static,
+    child: String,
+    stream: &'static str,
+    on_line: Option<Arc<LineHook>>,
+) {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(pipe).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    log_line(&child, stream, &line);
+                    if let Some(on_line) = &on_line {
+                        on_line(&line);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!(child, stream, "Failed to read output: {:?}", e);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Fields of `/proc/<pid>/stat` the usage is summed from
+#[derive(Debug, PartialEq)]
+struct ProcStat {
+    ppid: u32,
+    cpu_ticks: u64,
+    rss_pages: u64,
+}
+
+fn parse_proc_stat(stat: &str) -> Option<ProcStat> {
+    // The command name may hold spaces and parentheses, the other fields follow its last ')'
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields = fields.split_whitespace().collect::<Vec<_>>();
+    let field = |index: usize| fields.get(index)?.parse::<u64>().ok();
+    Some(ProcStat {
+        ppid: fields.get(1)?.parse().ok()?,
+        cpu_ticks: field(11)? + field(12)?,
+        rss_pages: field(21)?,
+    })
+}
+
+/// Units of the fields of `/proc/<pid>/stat`
+#[derive(Debug, Clone, Copy)]
+struct ProcUnits {
+    clock_ticks_per_second: u64,
+    page_size: u64,
+}
+
+/// A configuration value of the system, as `sysconf` reports it
+fn getconf(name: &str) -> Option<u64> {
+    let output = std::process::Command::new("getconf")
+        .arg(name)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+impl ProcUnits {
+    /// Asked once, blocking
+    fn get() -> Self {
+        static UNITS: OnceLock<ProcUnits> = OnceLock::new();
+        *UNITS.get_or_init(|| {
+            let units = ProcUnits {
+                clock_ticks_per_second: getconf("CLK_TCK").unwrap_or_else(|| {
+                    warn!(
+                        "Failed to get the clock ticks per second, assuming {}",
+                        DEFAULT_CLOCK_TICKS_PER_SECOND
+                    );
+                    DEFAULT_CLOCK_TICKS_PER_SECOND
+                }),
+                page_size: getconf("PAGESIZE").unwrap_or_else(|| {
+                    warn!(
+                        "Failed to get the page size, assuming {}",
+                        DEFAULT_PAGE_SIZE
+                    );
+                    DEFAULT_PAGE_SIZE
+                }),
+            };
+            debug!("Units of /proc/<pid>/stat: {:?}", units);
+            units
+        })
+    }
+}
+
+/// Sums the usage of `root` and all its descendants, Chrome runs a process per renderer
+fn sum_process_tree(root: u32, stats: &HashMap<u32, ProcStat>, units: ProcUnits) -> ResourceUsage {
+    let mut usage = ResourceUsage::default();
+    let mut pending = vec![root];
+    while let Some(pid) = pending.pop() {
+        let Some(stat) = stats.get(&pid) else {
+            continue;
+        };
+        usage.cpu_seconds += stat.cpu_ticks as f64 / units.clock_ticks_per_second as f64;
+        usage.rss_bytes += stat.rss_pages * units.page_size;
+        usage.processes += 1;
+        pending.extend(
+            stats
+                .iter()
+                .filter(|(_, child)| child.ppid == pid)
+                .map(|(child_pid, _)| *child_pid),
+        );
+    }
+    usage
+}
+
+fn resource_usage(pid: u32) -> std::io::Result<ResourceUsage> {
+    let mut stats = HashMap::new();
+    for entry in std::fs::read_dir("/proc")?.flatten() {
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse().ok())
+        else {
+            continue;
+        };
+        // Processes may exit while they are read
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        if let Some(stat) = parse_proc_stat(&stat) {
+            stats.insert(pid, stat);
+        }
+    }
+    Ok(sum_process_tree(pid, &stats, ProcUnits::get()))
+}
+
+/// Samples the usage of the child until it is gone for good
+fn start_resource_sampler(name: String, pid: Arc<AtomicU32>, gone: CancellationToken) {
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = gone.cancelled() => break,
+                _ = tokio::time::sleep(RESOURCE_SAMPLE_INTERVAL) => {}
+            }
+            let current_pid = pid.load(Ordering::Relaxed);
+            if current_pid == 0 {
+                continue;
+            }
+            let sample =
+                match tokio::task::spawn_blocking(move || resource_usage(current_pid)).await {
+                    Ok(Ok(sample)) => sample,
+                    Ok(Err(e)) => {
+                        warn!(child = name, "Failed to read resource usage: {:?}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!(child = name, "Failed to sample resource usage: {:?}", e);
+                        continue;
+                    }
+                };
+            info!(
+                child = name,
+                cpu_seconds = sample.cpu_seconds,
+                rss_bytes = sample.rss_bytes,
+                processes = sample.processes,
+                "Resource usage"
+            );
+        }
+    });
+}
+
+async fn wait_until_ready(
+    child: &mut Child,
+    name: &str,
+    readiness: Readiness,
+) -> anyhow::Result<()> {
+    let deadline = Instant::now() + readiness.timeout;
+    loop {
+        if TcpStream::connect(("127.0.0.1", readiness.port))
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!("{} exited before it was ready: {}", name, status);
+        }
+        anyhow::ensure!(
+            Instant::now() < deadline,
+            "{} not listening on port {} after {:?}",
+            name,
+            readiness.port,
+            readiness.timeout
+        );
+        tokio::time::sleep(READINESS_POLL_INTERVAL).await;
+    }
+}
+
+/// Spawns the child with its output forwarded to the logs, returns once it is ready
+async fn spawn_ready(
+    spec: &ChildSpec,
+    on_stderr_line: &Option<Arc<LineHook>>,
+    pid: &AtomicU32,
+) -> anyhow::Result<Child> {
+    let mut command = (spec.command)();
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Ensure the child is terminated when this process exits
+        .kill_on_drop(true);
+    info!(child = spec.name, "Starting {:?}", command);
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to start {}", spec.name))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get stdout"))?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("Failed to get stderr"))?;
+    forward_logs(stdout, spec.name.clone(), "stdout", None);
+    forward_logs(stderr, spec.name.clone(), "stderr", on_stderr_line.clone());
+    if let Some(readiness) = spec.readiness {
+        wait_until_ready(&mut child, &spec.name, readiness).await?;
+    }
+    pid.store(child.id().unwrap_or(0), Ordering::Relaxed);
+    Ok(child)
+}
+
+/// Starts a child process and keeps it running as its restart policy allows. It is killed
+/// when `stop_token` is cancelled. Returns once the first start is ready
+pub async fn start_child(
+    mut spec: ChildSpec,
+    stop_token: CancellationToken,
+) -> anyhow::Result<SupervisedChild> {
+    let pid = Arc::new(AtomicU32::new(0));
+    let on_stderr_line = spec.on_stderr_line.take().map(Arc::new);
+    let mut child = spawn_ready(&spec, &on_stderr_line, &pid).await?;
+
+    let gone = CancellationToken::new();
+    start_resource_sampler(spec.name.clone(), pid.clone(), gone.clone());
+    let exited = tokio::spawn(async move {
+        let _gone_guard = gone.drop_guard();
+        let name = spec.name.clone();
+        loop {
+            let mut exit = tokio::select! {
+                _ = stop_token.cancelled() => {
+                    let res = child.kill().await;
+                    info!(child = name, "Killed: {res:?}");
+                    return None;
+                }
+                exit = child.wait() => exit,
+            };
+            pid.store(0, Ordering::Relaxed);
+            error!(child = name, "Exited: {exit:?}");
+            // Retried until a start is ready or the restart policy gives up
+            loop {
+                let Some(delay) = spec
+                    .restart
+                    .as_mut()
+                    .and_then(|restart| restart.on_exit(Instant::now()))
+                else {
+                    return Some(exit);
+                };
+                warn!(child = name, "Restarting in {:?}", delay);
+                tokio::select! {
+                    _ = stop_token.cancelled() => return None,
+                    _ = tokio::time::sleep(delay) => {}
+                }
+                match spawn_ready(&spec, &on_stderr_line, &pid).await {
+                    Ok(restarted) => {
+                        info!(child = name, "Restarted");
+                        child = restarted;
+                        break;
+                    }
+                    Err(e) => {
+                        error!(child = name, "Failed to restart: {:?}", e);
+                        exit = Err(std::io::Error::other(format!("{:#}", e)));
+                    }
+                }
+            }
+        }
+    });
+    Ok(SupervisedChild { exited })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crash_window() {
+        let start = Instant::now();
+        let mut crash_window = CrashWindow::new(2, Duration::from_secs(60));
+        assert!(crash_window.record_crash(start));
+        assert!(crash_window.record_crash(start + Duration::from_secs(10)));
+        assert!(!crash_window.record_crash(start + Duration::from_secs(20)));
+        // The first two crashes left the window
+        assert!(crash_window.record_crash(start + Duration::from_secs(75)));
+    }
+
+    #[test]
+    fn test_restart_policy_backoff() {
+        let start = Instant::now();
+        let mut restart = RestartPolicy::new(3, Duration::from_secs(60))
+            .with_backoff(Duration::from_secs(1), Duration::from_secs(3));
+        assert_eq!(restart.on_exit(start), Some(Duration::from_secs(1)));
+        assert_eq!(
+            restart.on_exit(start + Duration::from_secs(5)),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            restart.on_exit(start + Duration::from_secs(10)),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(restart.on_exit(start + Duration::from_secs(15)), None);
+        // Back to the initial backoff once the earlier exits left the window
+        assert_eq!(
+            restart.on_exit(start + Duration::from_secs(200)),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(
+            parse_level("[12:34:0101/120000.123456:ERROR:gpu_init.cc(42)] GPU failed"),
+            Some(Level::ERROR)
+        );
+        assert_eq!(
+            parse_level("[12:34:0101/120000.123456:VERBOSE1:network.cc(7)] Request"),
+            Some(Level::DEBUG)
+        );
+        assert_eq!(
+            parse_level("WARNING:tzafonwright:Slow page"),
+            Some(Level::WARN)
+        );
+        assert_eq!(
+            parse_level("INFO:     Uvicorn running on http://0.0.0.0:1337"),
+            Some(Level::INFO)
+        );
+        assert_eq!(parse_level("DevTools listening on ws://127.0.0.1"), None);
+        assert_eq!(parse_level("Traceback (most recent call last):"), None);
+    }
+
+    #[test]
+    fn test_parse_proc_stat() {
+        let stat = "1234 (chrome (renderer)) S 1200 1234 1234 0 -1 4194560 100 0 0 0 \
+                    250 50 0 0 20 0 10 0 500 1000000 300 18446744073709551615";
+        assert_eq!(
+            parse_proc_stat(stat),
+            Some(ProcStat {
+                ppid: 1200,
+                cpu_ticks: 300,
+                rss_pages: 300,
+            })
+        );
+        assert_eq!(parse_proc_stat("1234 (chrome) S"), None);
+    }
+
+    #[test]
+    fn test_sum_process_tree() {
+        let stat = |ppid, cpu_ticks, rss_pages| ProcStat {
+            ppid,
+            cpu_ticks,
+            rss_pages,
+        };
+        let stats = HashMap::from([
+            (10, stat(1, 100, 10)),
+            (11, stat(10, 50, 5)),
+            (12, stat(11, 50, 5)),
+            (20, stat(1, 1000, 100)),
+        ]);
+        let units = ProcUnits {
+            clock_ticks_per_second: 100,
+            page_size: 16384,
+        };
+        assert_eq!(
+            sum_process_tree(10, &stats, units),
+            ResourceUsage {
+                cpu_seconds: 2.0,
+                rss_bytes: 20 * 16384,
+                processes: 3,
+            }
+        );
+        assert_eq!(
+            sum_process_tree(99, &stats, units),
+            ResourceUsage::default()
+        );
+    }
+}
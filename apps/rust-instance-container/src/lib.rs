@@ -1,10 +1,56 @@
+pub mod child_process;
+
 use clap::Parser;
-use shared::instance_manager::{InstanceId, InstanceType, Services};
+use shared::add_version;
+use shared::instance_manager::get_service_client::GetServiceClient;
+use shared::instance_manager::{InstanceId, InstanceType, Labels, Services};
+use shared::utils::{Availability, Liveness};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::task::JoinHandle;
 use tokio_util::sync::CancellationToken;
 use tracing::debug;
 
+/// Label of browsers that reset themselves and go back to the pool when their session ends,
+/// instead of being killed
+pub const RECYCLE_LABEL: &str = "recycle";
+/// Label of browsers that can load and save named profiles
+pub const SAVED_PROFILES_LABEL: &str = "saved_profiles";
+/// Endpoints of the saved profile service of a browser: loading takes `tenant` and `name` query
+/// parameters and answers 409 when another browser uses the profile, saving stores the loaded one
+pub const LOAD_SAVED_PROFILE_PATH: &str = "/saved-profile/load";
+pub const SAVE_SAVED_PROFILE_PATH: &str = "/saved-profile/save";
+
+/// Whether a tenant id or profile name can be used as a directory name in the profile store
+pub fn is_valid_profile_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 128
+        && !key.starts_with('.')
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Copies a file or directory recursively, handing the copies to `owner` if set
+pub fn copy_recursively(
+    from: &std::path::Path,
+    to: &std::path::Path,
+    owner: Option<u32>,
+) -> std::io::Result<()> {
+    if from.is_dir() {
+        std::fs::create_dir_all(to)?;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursively(&entry.path(), &to.join(entry.file_name()), owner)?;
+        }
+    } else {
+        std::fs::copy(from, to)?;
+    }
+    if owner.is_some() {
+        std::os::unix::fs::chown(to, owner, owner)?;
+    }
+    Ok(())
+}
+
 /// Spawns a task that reads lines from a pipe and logs them with the given prefix
 pub fn spawn_pipe_monitor(
     pipe: impl tokio::io::AsyncRead + Unpin + Send + 'static,
@@ -53,12 +99,18 @@ pub fn create_services_from_args(
     ip_address: &String,
     cdp_port: Option<u16>,
     tzafonwright_port: Option<u16>,
+    downloads_port: Option<u16>,
+    saved_profile_port: Option<u16>,
+    live_view_port: Option<u16>,
     _ssh_port: Option<u16>,
 ) -> Services {
     Services {
         timestamp_ms: None,
         chrome_debug_port_service: cdp_port.map(|port| format!("{}:{}", ip_address, port)),
         tzafonwright_service: tzafonwright_port.map(|port| format!("{}:{}", ip_address, port)),
+        downloads_service: downloads_port.map(|port| format!("{}:{}", ip_address, port)),
+        saved_profile_service: saved_profile_port.map(|port| format!("{}:{}", ip_address, port)),
+        live_view_service: live_view_port.map(|port| format!("{}:{}", ip_address, port)),
         // TODO: Add ssh service
         // ssh_service: ssh_port.map(|port| format!("{}:{}", ip_address, port)),
     }
@@ -68,19 +120,38 @@ pub async fn instance_manager_connection(
     instance_id: &InstanceId,
     instance_type: &InstanceType,
     services: Services,
+    labels: Labels,
+    liveness: Option<Liveness>,
     cancellation_token: &CancellationToken,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Availability> {
     use shared::{metrics::start_system_metrics_loop, utils::start_health_loop};
     let channel = instance_manager::get_channel(instance_manager_config).await?;
-    start_health_loop(
+    let availability = start_health_loop(
         instance_id,
         instance_type,
         &Some(services),
+        &Some(labels),
+        liveness,
         &channel,
         cancellation_token,
     )
     .await?;
 
     start_system_metrics_loop(instance_id, &channel, cancellation_token).await?;
-    Ok(())
+    Ok(availability)
+}
+
+/// Whether the instance is attached to a parent, e.g. a session of the ephemeral proxy
+pub async fn instance_has_parent(
+    instance_manager_config: &instance_manager::ClientArgs,
+    instance_id: &InstanceId,
+) -> anyhow::Result<bool> {
+    let channel = instance_manager::get_channel(instance_manager_config).await?;
+    let mut client = GetServiceClient::with_interceptor(channel, add_version);
+    let instance_description = client
+        .get_instance(tonic::Request::new(instance_id.clone()))
+        .await
+        .map_err(|e| anyhow::anyhow!("Error getting instance: {}", e))?
+        .into_inner();
+    Ok(instance_description.parent.is_some())
 }
@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use clap::Parser;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use shared::socket_gateway::access_log::AccessLog;
+use shared::socket_gateway::simple_gateway::{
+    GatewayOptions, HttpProxyConfig, UpstreamTimeouts, start_http_gateway_with_options,
+    start_simple_gateway_with_upstreams,
+};
+use shared::socket_gateway::upstream::{BalanceStrategy, UpstreamPool};
+
+/// Fronts several instances of a service, e.g. Tzafonwright or VLLM, with one address
+#[derive(Parser, Debug)]
+struct Args {
+    /// Port to accept connections on
+    #[clap(long)]
+    listen_port: u16,
+    /// `host:port` of an upstream, repeated for every upstream
+    #[clap(long = "upstream", required = true)]
+    upstreams: Vec<String>,
+    /// `round-robin`, `least-connections`, `hash-path` or `hash-header:<name>`
+    #[clap(long, default_value = "round-robin")]
+    strategy: BalanceStrategy,
+    /// Seconds an upstream is skipped after it failed to connect
+    #[clap(long, default_value_t = 10)]
+    unhealthy_period_secs: u64,
+    /// Milliseconds an upstream has to accept the connection before the next one is tried
+    #[clap(long, default_value_t = 3_000)]
+    connect_timeout_ms: u64,
+    /// Forward raw TCP instead of HTTP, consistent hashing then falls back to round-robin
+    #[clap(long)]
+    tcp: bool,
+    /// Seconds running HTTP connections are given to finish on shutdown before they are closed
+    #[clap(long, default_value_t = 30)]
+    drain_period_secs: u64,
+    /// File to write a JSON line per HTTP connection to, `-` for stdout
+    #[clap(long)]
+    access_log: Option<std::path::PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::try_parse()?;
+    tracing_subscriber::fmt().init();
+    let shutdown_signal = shared::utils::shutdown_signal()?;
+
+    let upstreams = UpstreamPool::new(args.upstreams, args.strategy)
+        .with_unhealthy_period(Duration::from_secs(args.unhealthy_period_secs));
+    let listen_addr = format!("0.0.0.0:{}", args.listen_port)
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Failed to parse listen address"))?;
+    let cancellation_token = CancellationToken::new();
+    let gateway = if args.tcp {
+        start_simple_gateway_with_upstreams(upstreams, listen_addr, &cancellation_token).await?;
+        None
+    } else {
+        let proxy_config =
+            HttpProxyConfig::with_upstreams(upstreams).with_timeouts(UpstreamTimeouts {
+                connect: Some(Duration::from_millis(args.connect_timeout_ms)),
+                ..Default::default()
+            });
+        let options =
+            GatewayOptions::new().with_drain_period(Duration::from_secs(args.drain_period_secs));
+        let options = match &args.access_log {
+            Some(path) => options.with_access_log(
+                AccessLog::open(path)
+                    .map_err(|e| anyhow::anyhow!("Failed to open access log: {:?}", e))?,
+            ),
+            None => options,
+        };
+        Some(
+            start_http_gateway_with_options(
+                proxy_config,
+                listen_addr,
+                options,
+                &cancellation_token,
+            )
+            .await?,
+        )
+    };
+    info!("Gateway listening on {}", listen_addr);
+
+    tokio::select! {
+        _ = cancellation_token.cancelled() => {}
+        signal = shutdown_signal => {
+            info!("Received {}, shutting down", signal);
+            cancellation_token.cancel();
+        }
+    }
+    if let Some(gateway) = gateway
+        && let Err(e) = gateway.await
+    {
+        error!("Gateway failed while draining: {:?}", e);
+    }
+    Ok(())
+}
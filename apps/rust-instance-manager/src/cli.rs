@@ -8,7 +8,8 @@ use instance_manager::{ClientArgs, get_channel};
 
 use shared::{
     add_version,
-    instance_manager::{AllInstancesQuery, InstanceType, get_service_client},
+    instance_manager::{AllInstancesQuery, InstanceType, Labels, get_service_client},
+    parse_labels,
 };
 
 fn parse_instance_type(s: &str) -> anyhow::Result<InstanceType> {
@@ -23,6 +24,9 @@ struct Args {
     pub has_parent: bool,
     #[clap(long)]
     pub alive: bool,
+    /// Only instances with these labels, e.g. `profile:default,headless:true`
+    #[clap(long, value_parser = parse_labels)]
+    pub labels: Option<Labels>,
     #[clap(flatten)]
     client_args: ClientArgs,
 }
@@ -39,6 +43,7 @@ async fn main() -> anyhow::Result<()> {
     let instance_ids = client
         .get_all_instances(Request::new(AllInstancesQuery {
             instance_type: args.instance_type as i32,
+            labels: args.labels.clone(),
         }))
         .await?
         .into_inner()
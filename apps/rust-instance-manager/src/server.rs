@@ -8,9 +8,10 @@ use tracing::info;
 use service::Service;
 
 use shared::instance_manager::get_service_server::GetServiceServer;
+use shared::instance_manager::info_service_server::InfoServiceServer;
 use shared::instance_manager::post_service_server::PostServiceServer;
 use shared::instance_manager::try_service_server::TryServiceServer;
-use shared::{PROTO_VERSION, check_version};
+use shared::{PROTO_VERSION, VersionPolicy};
 
 use instance_manager::{ServerArgs, get_server};
 
@@ -20,6 +21,10 @@ struct Args {
     debug_log: bool,
     #[clap(long, default_value_t = 4242)]
     status_page_port: u16,
+    /// Number of proto versions before the current one still accepted from clients, so
+    /// clients can be updated after the manager
+    #[clap(long, default_value_t = 1)]
+    proto_compatibility_window: usize,
     #[clap(flatten)]
     server_args: ServerArgs,
 }
@@ -40,7 +45,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "Listening on {}, using proto_version={}",
         addr, PROTO_VERSION
     );
-    let service = Service::new();
+    let version_policy = VersionPolicy::new(args.proto_compatibility_window);
+    info!(
+        "Accepting proto versions {:?}",
+        version_policy.accepted_versions()
+    );
+    let service = Service::new(version_policy.clone());
+    let check_version = move |req| version_policy.check(req);
     service.clone().start_kill_loop().await;
     service
         .clone()
@@ -49,16 +60,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     get_server(&args.server_args)?
         .add_service(TryServiceServer::with_interceptor(
             service.clone(),
-            check_version,
+            check_version.clone(),
         ))
         .add_service(PostServiceServer::with_interceptor(
             service.clone(),
-            check_version,
+            check_version.clone(),
         ))
         .add_service(GetServiceServer::with_interceptor(
             service.clone(),
             check_version,
         ))
+        // Without the version check, it tells clients whether their version is accepted
+        .add_service(InfoServiceServer::new(service.clone()))
         .serve(addr)
         .await?;
 
@@ -6,7 +6,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use axum::response::{Html, IntoResponse, Response as AxumResponse};
-use shared::get_timestamp_ms;
+use shared::{VersionCount, VersionPolicy, get_timestamp_ms};
 use tokio::sync::Mutex;
 use tokio::time::Instant;
 use tonic::{Request, Response, Status};
@@ -19,9 +19,10 @@ use shared::instance_manager::{
     AllInstancesQuery, AllInstancesResponse, InstanceDescription, InstanceId, InstanceType,
 };
 use shared::instance_manager::{
-    Bool, Children, KillInstanceRequest, KillReason, Relationship, TimestampMs, get_service_server,
-    post_service_server, try_service_server,
+    Bool, Children, HeartbeatResponse, KillInstanceRequest, KillReason, Relationship, TimestampMs,
+    get_service_server, info_service_server, post_service_server, try_service_server,
 };
+use shared::instance_manager::{LifecyclePolicy, ServerFeatures, ServerInfo, ServerInfoRequest};
 const ONE_HOUR: Duration = Duration::from_secs(60 * 60);
 const KILL_LOOP_INTERVAL: Duration = Duration::from_secs(1);
 
@@ -104,14 +105,29 @@ fn unhealth_instance(
         _ => None,
     }
 }
+/// Whether the instance was registered with all the given labels
+fn has_labels(
+    instance_description: &InstanceDescription,
+    labels: &HashMap<String, String>,
+) -> bool {
+    let instance_labels = instance_description
+        .labels
+        .as_ref()
+        .map(|labels| &labels.labels);
+    labels.iter().all(|(key, value)| {
+        instance_labels.is_some_and(|instance_labels| instance_labels.get(key) == Some(value))
+    })
+}
 struct InnerService {
     instance_description: HashMap<String, InstanceDescription>,
+    version_policy: VersionPolicy,
 }
 #[derive(Clone)]
 pub struct Service(Arc<Mutex<InnerService>>);
 
 pub fn create_status_page(
     instance_descriptions: Vec<InstanceDescription>,
+    version_counts: Vec<VersionCount>,
 ) -> anyhow::Result<String> {
     let browsers: anyhow::Result<Vec<status_page::Browser>> = instance_descriptions
         .into_iter()
@@ -166,7 +182,7 @@ pub fn create_status_page(
     let browsers = browsers.map_err(axum::Error::new)?;
 
     let timestamp_ms = get_timestamp_ms().timestamp_ms;
-    let html = status_page::render(&browsers, timestamp_ms)?;
+    let html = status_page::render(&browsers, &version_counts, timestamp_ms)?;
     Ok(html)
 }
 
@@ -236,12 +252,17 @@ async fn handle_get_browsers(
         let mut last_value = cache.lock().await;
         let now = Instant::now();
         if now.duration_since(last_value.0) > Duration::from_secs(1) {
-            let instance_descriptions = {
+            let (instance_descriptions, version_counts) = {
                 let lock = service.0.lock().await;
-                lock.instance_description.values().cloned().collect()
+                (
+                    lock.instance_description.values().cloned().collect(),
+                    lock.version_policy.version_counts(),
+                )
             };
-            match tokio::task::spawn_blocking(move || create_status_page(instance_descriptions))
-                .await
+            match tokio::task::spawn_blocking(move || {
+                create_status_page(instance_descriptions, version_counts)
+            })
+            .await
             {
                 Ok(Ok(html)) => {
                     last_value.0 = now;
@@ -273,6 +294,7 @@ impl Service {
                         kill_instance_request: Some(KillInstanceRequest {
                             kill_reason: kill_reason as i32,
                             timestamp_ms: Some(current_timestamp_ms),
+                            message: None,
                         }),
                         ..Default::default()
                     }
@@ -286,6 +308,7 @@ impl Service {
             let instance_id = instance.instance_id.clone();
             let kill_reason = instance
                 .kill_instance_request
+                .as_ref()
                 .map(|kill_instance_request| kill_instance_request.kill_reason)
                 .and_then(|kill_reason| KillReason::try_from(kill_reason).ok());
 
@@ -378,9 +401,11 @@ impl Service {
             system_metrics,
             gpu_metrics,
             llm_metrics,
+            release_request,
             // Not used fields
             created_timestamp_ms: None,
             instance_type: None,
+            labels: None,
         } = request
         {
             let mut lock = self.0.lock().await;
@@ -391,6 +416,18 @@ impl Service {
                     // Instance is dead
                     return Ok(Response::new(Bool { value: false }));
                 }
+                // Only the parent an instance was handed out to can release it
+                if let Some(release_request) = &release_request {
+                    let parent_instance_id = instance_description
+                        .parent
+                        .as_ref()
+                        .and_then(|parent| parent.instance_id.as_ref());
+                    if parent_instance_id.is_none()
+                        || parent_instance_id != release_request.requested_by.as_ref()
+                    {
+                        return Ok(Response::new(Bool { value: false }));
+                    }
+                }
                 // Adding a parent
                 if let Some(parent_relationship) = &parent {
                     let parent_instance_id =
@@ -439,6 +476,7 @@ impl Service {
                 update_instance_description(instance_description, system_metrics);
                 update_instance_description(instance_description, gpu_metrics);
                 update_instance_description(instance_description, llm_metrics);
+                update_instance_description(instance_description, release_request);
             }
             if let Some(children) = &children {
                 for child in children.children.iter() {
@@ -450,6 +488,7 @@ impl Service {
                         child_instance_description,
                         Some(Relationship {
                             instance_id: Some(instance_id.clone()),
+                            tenant_id: child.tenant_id.clone(),
                             ..Default::default()
                         }),
                     );
@@ -465,6 +504,7 @@ impl Service {
                     Some(Children {
                         children: vec![Relationship {
                             instance_id: Some(instance_id.clone()),
+                            tenant_id: parent.tenant_id.clone(),
                             ..Default::default()
                         }],
                     }),
@@ -473,7 +513,12 @@ impl Service {
             let instance_description = instance_descriptions
                 .get_mut(&instance_id.instance_id)
                 .ok_or(Status::not_found("Instance not found"))?;
-            if instance_description.kill_instance_request.is_some() {
+            // An instance shutting down gracefully releases its children itself
+            if instance_description
+                .kill_instance_request
+                .as_ref()
+                .is_some_and(|request| request.kill_reason != KillReason::Shutdown as i32)
+            {
                 let mut children = instance_description
                     .children
                     .clone()
@@ -491,6 +536,7 @@ impl Service {
                             Some(KillInstanceRequest {
                                 kill_reason: KillReason::ParentDead as i32,
                                 timestamp_ms: None,
+                                message: None,
                             }),
                         );
                         children.extend_from_slice(
@@ -508,6 +554,36 @@ impl Service {
         }
         Ok(Response::new(Bool { value: true }))
     }
+    /// Clears the parent of a released instance and removes it from the parent's children
+    async fn detach_instance(&self, instance_id: &InstanceId) -> Result<Response<Bool>, Status> {
+        let mut lock = self.0.lock().await;
+        let instance_descriptions = &mut lock.instance_description;
+        let Some(instance_description) = instance_descriptions.get_mut(&instance_id.instance_id)
+        else {
+            return Ok(Response::new(Bool { value: false }));
+        };
+        if instance_description.kill_instance_request.is_some()
+            || instance_description.release_request.is_none()
+        {
+            return Ok(Response::new(Bool { value: false }));
+        }
+        instance_description.release_request = None;
+        let parent_instance_id = instance_description
+            .parent
+            .take()
+            .and_then(|parent| parent.instance_id);
+        if let Some(parent_instance_description) =
+            parent_instance_id.and_then(|parent_instance_id| {
+                instance_descriptions.get_mut(&parent_instance_id.instance_id)
+            })
+            && let Some(children) = &mut parent_instance_description.children
+        {
+            children
+                .children
+                .retain(|child| child.instance_id.as_ref() != Some(instance_id));
+        }
+        Ok(Response::new(Bool { value: true }))
+    }
     async fn get_instance_description<T: HasInstanceId>(
         &self,
         request: &T,
@@ -545,8 +621,9 @@ impl Service {
                 entry.insert(InstanceDescription {
                     instance_id: Some(instance_id.clone()),
                     created_timestamp_ms: Some(get_timestamp_ms()),
-                    // remove instance_type from request
+                    // remove instance_type and labels from request
                     instance_type: request.instance_type.take(),
+                    labels: request.labels.take(),
                     ..Default::default()
                 });
                 instance_id_key
@@ -575,9 +652,10 @@ impl Service {
 }
 
 impl Service {
-    pub fn new() -> Self {
+    pub fn new(version_policy: VersionPolicy) -> Self {
         Service(Arc::new(Mutex::new(InnerService {
             instance_description: HashMap::new(),
+            version_policy,
         })))
     }
 }
@@ -597,6 +675,7 @@ impl try_service_server::TryService for Service {
                 children: _,
                 services: _,
                 parent: _,
+                labels: _,
                 // Not used fields
                 health_check: None,
                 proxy_metrics: None,
@@ -604,6 +683,7 @@ impl try_service_server::TryService for Service {
                 gpu_metrics: None,
                 llm_metrics: None,
                 kill_instance_request: None,
+                release_request: None,
             } => {
                 self.insert_new_instance_description(instance_description)
                     .await
@@ -623,6 +703,7 @@ impl try_service_server::TryService for Service {
             health_check: _,
             children: _,
             parent: _,
+            release_request: _,
             // Not used fields
             created_timestamp_ms: None,
             instance_type: None,
@@ -630,6 +711,7 @@ impl try_service_server::TryService for Service {
             system_metrics: None,
             gpu_metrics: None,
             llm_metrics: None,
+            labels: None,
         } = &instance_description
         {
             self.apply_to_instance_description(instance_description)
@@ -638,6 +720,50 @@ impl try_service_server::TryService for Service {
             Err(Status::invalid_argument("Invalid request"))
         }
     }
+    async fn try_detach_instance(
+        &self,
+        request: Request<InstanceId>,
+    ) -> Result<Response<Bool>, Status> {
+        let instance_id = request.into_inner();
+        self.detach_instance(&instance_id).await
+    }
+    async fn try_heartbeat(
+        &self,
+        request: Request<InstanceDescription>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        let instance_description = request.into_inner();
+        let InstanceDescription {
+            instance_id: Some(instance_id),
+            health_check: Some(_),
+            ..
+        } = &instance_description
+        else {
+            return Err(Status::invalid_argument("Invalid request"));
+        };
+        let instance_id = instance_id.clone();
+        // Only the health check is taken, a heartbeat can't change anything else
+        let value = self
+            .apply_to_instance_description(InstanceDescription {
+                instance_id: Some(instance_id.clone()),
+                health_check: instance_description.health_check,
+                ..Default::default()
+            })
+            .await?
+            .into_inner()
+            .value;
+        let release_requested = value
+            && self
+                .0
+                .lock()
+                .await
+                .instance_description
+                .get(&instance_id.instance_id)
+                .is_some_and(|instance_description| instance_description.release_request.is_some());
+        Ok(Response::new(HeartbeatResponse {
+            value,
+            release_requested,
+        }))
+    }
 }
 
 #[tonic::async_trait]
@@ -646,7 +772,11 @@ impl get_service_server::GetService for Service {
         &self,
         request: Request<AllInstancesQuery>,
     ) -> Result<Response<AllInstancesResponse>, Status> {
-        let AllInstancesQuery { instance_type } = request.into_inner();
+        let AllInstancesQuery {
+            instance_type,
+            labels,
+        } = request.into_inner();
+        let labels = labels.unwrap_or_default().labels;
         let lock = self.0.lock().await;
         let instance_ids = lock
             .instance_description
@@ -656,6 +786,7 @@ impl get_service_server::GetService for Service {
             })
             .filter(|instance_description| instance_description.health_check.is_some())
             .filter(|instance_description| instance_description.kill_instance_request.is_none())
+            .filter(|instance_description| has_labels(instance_description, &labels))
             .map(|instance_description| {
                 instance_description
                     .instance_id
@@ -698,6 +829,8 @@ impl post_service_server::PostService for Service {
             health_check: None,
             kill_instance_request: None,
             services: None,
+            labels: None,
+            release_request: None,
         } = &instance_description
         {
             self.apply_to_instance_description(instance_description)
@@ -707,3 +840,34 @@ impl post_service_server::PostService for Service {
         }
     }
 }
+
+#[tonic::async_trait]
+impl info_service_server::InfoService for Service {
+    async fn get_server_info(
+        &self,
+        request: Request<ServerInfoRequest>,
+    ) -> Result<Response<ServerInfo>, Status> {
+        let client_version = request.into_inner().proto_version;
+        let version_policy = self.0.lock().await.version_policy.clone();
+        Ok(Response::new(ServerInfo {
+            proto_version: shared::PROTO_VERSION.to_string(),
+            accepted_proto_versions: version_policy.accepted_versions().to_vec(),
+            client_version_accepted: version_policy.accepts(&client_version),
+            build_version: env!("CARGO_PKG_VERSION").to_string(),
+            // What this build serves
+            features: Some(ServerFeatures {
+                subscriptions: false,
+                leases: false,
+                labels: true,
+                persistence: false,
+                recycling: true,
+            }),
+            lifecycle_policy: Some(LifecyclePolicy {
+                health_check_timeout_ms: CHROME_BROWSER_TIMEOUT_MS,
+                session_lifetime_ms: CHROME_BROWSER_SESSION_LIFETIME_MS,
+                max_lifetime_ms: CHROME_BROWSER_MAX_LIFETIME_MS,
+                kill_loop_interval_ms: KILL_LOOP_INTERVAL.as_millis() as u64,
+            }),
+        }))
+    }
+}
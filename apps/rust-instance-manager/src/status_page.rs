@@ -2,6 +2,7 @@ use std::fmt::{self, Display};
 
 use askama::Template;
 
+use shared::VersionCount;
 use shared::instance_manager::{InstanceDescription, InstanceId, KillReason, TimestampMs};
 const MAX_ITEMS: usize = 30;
 
@@ -65,6 +66,14 @@ struct Connection {
     state: String,
 }
 
+struct ProtoVersion {
+    version: String,
+    label: String,
+    /// current, accepted or rejected
+    state: String,
+    requests: u64,
+}
+
 #[derive(Template)]
 #[template(path = "dashboard.html")]
 struct WarmPoolTemplate {
@@ -73,6 +82,7 @@ struct WarmPoolTemplate {
     available_browsers: i32,
     connections: Vec<Connection>,
     registrations: Vec<Registrations>,
+    proto_versions: Vec<ProtoVersion>,
 }
 
 impl WarmPoolTemplate {
@@ -90,7 +100,11 @@ impl WarmPoolTemplate {
     }
 }
 
-pub fn render(browsers: &[Browser], current_time_ms: u64) -> Result<String, askama::Error> {
+pub fn render(
+    browsers: &[Browser],
+    version_counts: &[VersionCount],
+    current_time_ms: u64,
+) -> Result<String, askama::Error> {
     let mut connections: Vec<Connection> = browsers
         .iter()
         .filter_map(|b| match b {
@@ -119,6 +133,20 @@ pub fn render(browsers: &[Browser], current_time_ms: u64) -> Result<String, aska
     connections.truncate(MAX_ITEMS);
     registrations.sort_by_key(|r| r.time_since_registered_ms);
     registrations.truncate(MAX_ITEMS);
+    let proto_versions = version_counts
+        .iter()
+        .map(|count| ProtoVersion {
+            version: count.version.clone(),
+            label: truncated_string(&count.version),
+            state: match (count.version == shared::PROTO_VERSION, count.accepted) {
+                (true, _) => "current",
+                (false, true) => "accepted",
+                (false, false) => "rejected",
+            }
+            .to_string(),
+            requests: count.requests,
+        })
+        .collect();
 
     WarmPoolTemplate {
         all_browsers: all_browsers(browsers),
@@ -126,6 +154,7 @@ pub fn render(browsers: &[Browser], current_time_ms: u64) -> Result<String, aska
         available_browsers: available_browsers(browsers),
         connections,
         registrations,
+        proto_versions,
     }
     .render()
 }
@@ -137,8 +166,12 @@ pub struct SingleInstancePageTemplate {
     created_timestamp_ms: String,
     state_info: String,
     parent: InstanceIdWithUrl,
+    tenant: String,
     debug_info: String,
     services: Vec<String>,
+    /// Base URL of the live view of a running browser
+    live_view_url: Option<String>,
+    labels: Vec<String>,
     system_metrics: String,
     children: Vec<InstanceIdWithUrl>,
 }
@@ -187,10 +220,15 @@ impl SingleInstancePageTemplate {
             system_metrics,
             children,
             kill_instance_request,
+            labels,
             ..
         } = instance_description;
         let instance_id = format_instance_id(instance_id);
         let created_timestamp_ms = format_timestamp_ms(&created_timestamp_ms);
+        let tenant = parent
+            .as_ref()
+            .and_then(|parent| parent.tenant_id.clone())
+            .unwrap_or_else(|| "No tenant".to_string());
         let parent = match parent {
             Some(parent) => format_instance_id(&parent.instance_id),
             None => InstanceIdWithUrl {
@@ -198,10 +236,18 @@ impl SingleInstancePageTemplate {
                 url: None,
             },
         };
+        let live_view_url = services
+            .as_ref()
+            .and_then(|services| services.live_view_service.as_ref())
+            .filter(|_| kill_instance_request.is_none())
+            .map(|service| format!("http://{}", service));
         let services = match services {
             Some(services) => [
                 ("Chrome debug", services.chrome_debug_port_service.clone()),
                 ("Tzafonwright", services.tzafonwright_service.clone()),
+                ("Downloads", services.downloads_service.clone()),
+                ("Saved profiles", services.saved_profile_service.clone()),
+                ("Live view", services.live_view_service.clone()),
             ]
             .into_iter()
             .filter_map(|(name, service)| service.map(|service| (name, service)))
@@ -209,6 +255,14 @@ impl SingleInstancePageTemplate {
             .collect::<Vec<_>>(),
             None => vec![],
         };
+        let mut labels = labels.as_ref().map_or_else(Vec::new, |labels| {
+            labels
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{}: {}", key, value))
+                .collect::<Vec<_>>()
+        });
+        labels.sort();
         let system_metrics = match system_metrics {
             Some(system_metrics) => format!("{:?}", system_metrics),
             None => "No system metrics".to_string(),
@@ -224,11 +278,15 @@ impl SingleInstancePageTemplate {
             Some(kill_instance_request) => {
                 let kill_reason = KillReason::try_from(kill_instance_request.kill_reason)
                     .unwrap_or_else(|_| KillReason::DefaultKillReason);
-                format!(
+                let state_info = format!(
                     "Was killed for {:?} at {}",
                     kill_reason,
                     format_timestamp_ms(&kill_instance_request.timestamp_ms)
-                )
+                );
+                match &kill_instance_request.message {
+                    Some(message) => format!("{}: {}", state_info, message),
+                    None => state_info,
+                }
             }
             None => "Is alive".to_string(),
         };
@@ -238,7 +296,10 @@ impl SingleInstancePageTemplate {
             created_timestamp_ms,
             state_info,
             parent,
+            tenant,
             services,
+            live_view_url,
+            labels,
             system_metrics,
             children,
             debug_info: format!("{:?}", instance_description),
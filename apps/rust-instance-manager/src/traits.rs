@@ -4,7 +4,8 @@ use shared::{
     get_timestamp_ms,
     instance_manager::{
         Children, GpuMetrics, HealthCheck, InstanceDescription, InstanceId, KillInstanceRequest,
-        LlmMetrics, ProxyMetrics, Relationship, Services, SystemMetrics, TimestampMs,
+        LlmMetrics, ProxyMetrics, Relationship, ReleaseRequest, Services, SystemMetrics,
+        TimestampMs,
     },
 };
 pub fn update_instance_description<T: SetTimestamp + AddToInstanceDescription>(
@@ -102,5 +103,8 @@ impl_instance_traits!(
     kill_instance_request
 );
 
+impl_instance_traits!(ReleaseRequest, set_timestamp);
+impl_instance_traits!(ReleaseRequest, add_to_instance_description, release_request);
+
 impl_instance_traits!(Services, set_timestamp);
 impl_instance_traits!(Services, add_to_instance_description, services);
@@ -40,6 +40,15 @@ fn compile_protos(folder: PathBuf) -> Result<(), anyhow::Error> {
     let out_dir = std::env::var("OUT_DIR").unwrap();
     let hashfile = PathBuf::from(out_dir).join("proto_version");
     std::fs::write(hashfile, &hash)?;
+    println!(
+        "cargo:rerun-if-changed={}",
+        folder.join("proto_version_history").display()
+    );
+    let history = std::fs::read_to_string(folder.join("proto_version_history")).unwrap_or_default();
+    std::fs::write(
+        PathBuf::from(std::env::var("OUT_DIR")?).join("proto_version_history"),
+        history,
+    )?;
 
     Ok(())
 }
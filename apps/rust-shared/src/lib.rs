@@ -5,18 +5,117 @@ pub mod metrics;
 pub mod socket_gateway;
 pub mod utils;
 
-use instance_manager::TimestampMs;
+use instance_manager::{Labels, TimestampMs};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::{SystemTime, UNIX_EPOCH};
 use tonic::metadata::MetadataValue;
 use tonic::{Request, Status};
+use tracing::{info, warn};
 
 pub const PROTO_VERSION: &str = include_str!(concat!(env!("OUT_DIR"), "/proto_version"));
+/// Versions replaced by `PROTO_VERSION`, one per line, newest first
+pub const PROTO_VERSION_HISTORY: &str =
+    include_str!(concat!(env!("OUT_DIR"), "/proto_version_history"));
 
-pub fn check_version(req: Request<()>) -> Result<Request<()>, Status> {
-    match req.metadata().get("proto_version") {
-        Some(t) if PROTO_VERSION == t => Ok(req),
-        Some(_) => Err(Status::failed_precondition("Wrong protocol versions")),
-        _ => Err(Status::failed_precondition("No version supplied")),
+/// Label counting the requests of every version that is not accepted, including requests
+/// without one. Clients choose the version they send, so only accepted ones get a count of
+/// their own
+const REJECTED_VERSIONS: &str = "rejected";
+
+/// Number of requests of one client proto version
+#[derive(Debug, Clone)]
+pub struct VersionCount {
+    pub version: String,
+    pub accepted: bool,
+    pub requests: u64,
+}
+
+/// Accepts clients on the current proto version and the `compatibility_window` versions
+/// before it, and counts the requests of every accepted version and of all rejected ones
+#[derive(Clone)]
+pub struct VersionPolicy {
+    accepted: Arc<Vec<String>>,
+    counts: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl VersionPolicy {
+    pub fn new(compatibility_window: usize) -> Self {
+        let accepted = std::iter::once(PROTO_VERSION)
+            .chain(
+                PROTO_VERSION_HISTORY
+                    .lines()
+                    .map(str::trim)
+                    .filter(|version| !version.is_empty())
+                    .take(compatibility_window),
+            )
+            .map(str::to_string)
+            .collect();
+        VersionPolicy {
+            accepted: Arc::new(accepted),
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Current version first
+    pub fn accepted_versions(&self) -> &[String] {
+        &self.accepted
+    }
+
+    pub fn accepts(&self, version: &str) -> bool {
+        self.accepted.iter().any(|accepted| accepted == version)
+    }
+
+    /// Interceptor checking the `proto_version` of a request
+    pub fn check(&self, req: Request<()>) -> Result<Request<()>, Status> {
+        let version = req
+            .metadata()
+            .get("proto_version")
+            .map(|v| v.to_str().unwrap_or_default().to_string());
+        let accepted = version
+            .as_ref()
+            .is_some_and(|version| self.accepted.contains(version));
+        let label = match &version {
+            Some(version) if accepted => version.as_str(),
+            _ => REJECTED_VERSIONS,
+        };
+        if let Ok(mut counts) = self.counts.lock() {
+            let count = counts.entry(label.to_string()).or_default();
+            // Logged once per label, the counts are on the status page
+            if *count == 0 && label != PROTO_VERSION {
+                if accepted {
+                    info!("Client on previous proto version {}", label);
+                } else {
+                    warn!("Rejecting client on proto version {:?}", version);
+                }
+            }
+            *count += 1;
+        }
+        match version {
+            _ if accepted => Ok(req),
+            Some(_) => Err(Status::failed_precondition(format!(
+                "Wrong protocol versions, accepted: {}",
+                self.accepted.join(", ")
+            ))),
+            None => Err(Status::failed_precondition("No version supplied")),
+        }
+    }
+
+    /// Requests per client version, most used first
+    pub fn version_counts(&self) -> Vec<VersionCount> {
+        let Ok(counts) = self.counts.lock() else {
+            return Vec::new();
+        };
+        let mut counts = counts
+            .iter()
+            .map(|(version, requests)| VersionCount {
+                version: version.clone(),
+                accepted: self.accepted.contains(version),
+                requests: *requests,
+            })
+            .collect::<Vec<_>>();
+        counts.sort_by_key(|count| std::cmp::Reverse(count.requests));
+        counts
     }
 }
 
@@ -36,3 +135,71 @@ pub fn get_timestamp_ms() -> TimestampMs {
             .as_millis() as u64,
     }
 }
+
+/// Parses labels written as `key:value,key:value`, values may contain colons
+pub fn parse_labels(labels: &str) -> anyhow::Result<Labels> {
+    let labels = labels
+        .split(',')
+        .map(str::trim)
+        .filter(|label| !label.is_empty())
+        .map(|label| {
+            label
+                .split_once(':')
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .ok_or_else(|| anyhow::anyhow!("Label {:?} is not written as key:value", label))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Labels { labels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_parse_labels() {
+        let labels = parse_labels("profile:de, proxy_server:http://proxy:3128,").unwrap();
+        assert_eq!(labels.labels.len(), 2);
+        assert_eq!(labels.labels["profile"], "de");
+        assert_eq!(labels.labels["proxy_server"], "http://proxy:3128");
+        assert!(parse_labels("profile").is_err());
+    }
+
+    fn request(version: Option<&str>) -> Request<()> {
+        let mut req = Request::new(());
+        if let Some(version) = version
+            && let Ok(version) = version.parse()
+        {
+            req.metadata_mut().insert("proto_version", version);
+        }
+        req
+    }
+
+    #[test]
+    fn test_version_policy() {
+        let previous = PROTO_VERSION_HISTORY.lines().next();
+        let strict = VersionPolicy::new(0);
+        assert!(strict.check(request(Some(PROTO_VERSION))).is_ok());
+        assert!(strict.check(request(None)).is_err());
+        if let Some(previous) = previous {
+            assert!(strict.check(request(Some(previous))).is_err());
+            assert!(VersionPolicy::new(1).check(request(Some(previous))).is_ok());
+        }
+        assert!(strict.check(request(Some("other"))).is_err());
+        assert!(strict.check(request(Some(PROTO_VERSION))).is_ok());
+
+        let counts = strict.version_counts();
+        assert_eq!(counts.len(), 2);
+        assert!(
+            counts
+                .iter()
+                .any(|c| c.version == PROTO_VERSION && c.requests == 2 && c.accepted)
+        );
+        assert!(
+            counts
+                .iter()
+                .any(|c| c.version == REJECTED_VERSIONS && !c.accepted)
+        );
+    }
+}
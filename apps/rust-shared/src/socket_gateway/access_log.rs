@@ -0,0 +1,115 @@
+use std::fs::OpenOptions;
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::Serialize;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Lines queued for the writer task before new ones are dropped
+const QUEUE_SIZE: usize = 4096;
+
+/// One proxied (or rejected) client connection
+#[derive(Debug, Serialize)]
+pub struct AccessLogEntry {
+    pub start_timestamp_ms: u64,
+    pub end_timestamp_ms: u64,
+    pub duration_ms: u64,
+    pub client_addr: SocketAddr,
+    pub method: Option<String>,
+    /// Path requested by the client, without its query since it may hold credentials
+    pub path: Option<String>,
+    pub upstream: Option<String>,
+    pub instance_id: Option<String>,
+    /// Status of the upstream response, or of the error response of a rejected connection
+    pub status: Option<u16>,
+    pub client_to_server_bytes: u64,
+    pub server_to_client_bytes: u64,
+    pub close_reason: String,
+}
+
+/// Writes one JSON line per connection handled by a gateway. Lines are written by a task of
+/// their own, so connections never wait on the file
+#[derive(Clone)]
+pub struct AccessLog(mpsc::Sender<Vec<u8>>);
+
+impl AccessLog {
+    /// Must be called from within a tokio runtime
+    pub fn stdout() -> Self {
+        Self::spawn(Box::new(tokio::io::stdout()))
+    }
+    /// Appends to the file at `path`, `-` logs to stdout. Must be called from within a tokio
+    /// runtime
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if path == Path::new("-") {
+            return Ok(Self::stdout());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context("Failed to open access log")?;
+        Ok(Self::spawn(Box::new(tokio::fs::File::from_std(file))))
+    }
+
+    fn spawn(writer: Box<dyn AsyncWrite + Send + Unpin>) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(write_lines(receiver, writer));
+        AccessLog(sender)
+    }
+
+    pub fn write(&self, entry: &AccessLogEntry) {
+        let Ok(mut line) = serde_json::to_vec(entry) else {
+            warn!("Failed to serialize access log entry");
+            return;
+        };
+        line.push(b'\n');
+        if let Err(e) = self.0.try_send(line) {
+            warn!("Dropped access log entry: {}", e);
+        }
+    }
+}
+
+/// Writes queued lines, flushing whenever the queue runs empty
+pub(crate) async fn write_lines(
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+) {
+    let mut writer = BufWriter::new(writer);
+    while let Some(line) = receiver.recv().await {
+        let mut result = writer.write_all(&line).await;
+        while result.is_ok()
+            && let Ok(line) = receiver.try_recv()
+        {
+            result = writer.write_all(&line).await;
+        }
+        if let Err(e) = result.and(writer.flush().await) {
+            warn!("Failed to write access log: {:?}", e);
+        }
+    }
+}
+
+/// Status code of a response starting with a HTTP/1.x status line
+pub(crate) fn parse_status(data: &[u8]) -> Option<u16> {
+    let rest = data.strip_prefix(b"HTTP/1.")?;
+    let status = rest.get(2..5)?;
+    std::str::from_utf8(status).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(
+            parse_status(b"HTTP/1.1 101 Switching Protocols\r\n"),
+            Some(101)
+        );
+        assert_eq!(parse_status(b"HTTP/1.0 404"), Some(404));
+        assert_eq!(parse_status(b"HTTP/1.1 10"), None);
+        assert_eq!(parse_status(b"\x81\x05hello"), None);
+    }
+}
@@ -0,0 +1,290 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+
+/// Message of the error a connection is closed with once it reached its byte cap
+pub const TRAFFIC_CAP_EXCEEDED: &str = "Traffic cap exceeded";
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub bytes_per_second: u64,
+    /// Bytes that can be sent at once after being idle
+    pub burst_bytes: u64,
+}
+
+impl RateLimit {
+    /// Allows bursts of one second worth of traffic
+    pub fn per_second(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            burst_bytes: bytes_per_second,
+        }
+    }
+}
+
+/// Token bucket allowed to go into debt, a read or write is never split, the next one waits
+/// until the debt is paid back instead
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst_bytes as f64,
+            last_refill: Instant::now(),
+        }
+    }
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.bytes_per_second as f64)
+            .min(self.limit.burst_bytes as f64);
+        self.last_refill = now;
+    }
+    fn consume(&mut self, bytes: usize, now: Instant) {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+    }
+    /// Time until the bucket is out of debt
+    fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.tokens >= 0.0 || self.limit.bytes_per_second == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / self.limit.bytes_per_second as f64)
+    }
+}
+
+type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+fn shared_bucket(limit: Option<RateLimit>) -> Option<SharedBucket> {
+    limit.map(|limit| Arc::new(Mutex::new(TokenBucket::new(limit))))
+}
+
+/// Rate limits shared by all connections it is attached to, e.g. all connections of a tenant
+#[derive(Debug, Clone)]
+pub struct SharedRateLimiter {
+    upload: Option<SharedBucket>,
+    download: Option<SharedBucket>,
+}
+
+impl SharedRateLimiter {
+    pub fn new(upload: Option<RateLimit>, download: Option<RateLimit>) -> Self {
+        Self {
+            upload: shared_bucket(upload),
+            download: shared_bucket(download),
+        }
+    }
+}
+
+/// Traffic limits of a proxied connection. Upload is client to server, download server to client
+#[derive(Debug, Clone, Default)]
+pub struct TrafficLimits {
+    pub upload: Option<RateLimit>,
+    pub download: Option<RateLimit>,
+    /// Bytes in both directions after which the connection is closed
+    pub max_total_bytes: Option<u64>,
+    pub shared: Vec<SharedRateLimiter>,
+}
+
+impl TrafficLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_upload(mut self, limit: RateLimit) -> Self {
+        self.upload = Some(limit);
+        self
+    }
+    pub fn with_download(mut self, limit: RateLimit) -> Self {
+        self.download = Some(limit);
+        self
+    }
+    pub fn with_max_total_bytes(mut self, max_total_bytes: u64) -> Self {
+        self.max_total_bytes = Some(max_total_bytes);
+        self
+    }
+    pub fn with_shared(mut self, limiter: SharedRateLimiter) -> Self {
+        self.shared.push(limiter);
+        self
+    }
+}
+
+/// Buckets of one direction, waits until all of them are out of debt
+#[derive(Default)]
+struct Direction {
+    buckets: Vec<SharedBucket>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Direction {
+    fn new(own: Option<RateLimit>, shared: impl Iterator<Item = Option<SharedBucket>>) -> Self {
+        Self {
+            buckets: shared_bucket(own)
+                .into_iter()
+                .chain(shared.flatten())
+                .collect(),
+            delay: None,
+        }
+    }
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(delay) = &mut self.delay {
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+            let now = Instant::now();
+            let wait = self
+                .buckets
+                .iter()
+                .filter_map(|bucket| bucket.lock().ok().map(|mut b| b.wait_time(now)))
+                .max()
+                .unwrap_or_default();
+            if wait.is_zero() {
+                return Poll::Ready(());
+            }
+            self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+    fn consume(&self, bytes: usize) {
+        let now = Instant::now();
+        for bucket in &self.buckets {
+            if let Ok(mut bucket) = bucket.lock() {
+                bucket.consume(bytes, now);
+            }
+        }
+    }
+}
+
+/// Server stream shaped to the traffic limits of its connection
+pub(crate) struct ShapedStream<S> {
+    inner: S,
+    upload: Direction,
+    download: Direction,
+    max_total_bytes: Option<u64>,
+    total_bytes: u64,
+}
+
+impl<S> ShapedStream<S> {
+    pub(crate) fn new(inner: S, limits: &TrafficLimits) -> Self {
+        Self {
+            inner,
+            upload: Direction::new(
+                limits.upload,
+                limits.shared.iter().map(|shared| shared.upload.clone()),
+            ),
+            download: Direction::new(
+                limits.download,
+                limits.shared.iter().map(|shared| shared.download.clone()),
+            ),
+            max_total_bytes: limits.max_total_bytes,
+            total_bytes: 0,
+        }
+    }
+    pub(crate) fn cap_exceeded(&self) -> bool {
+        self.max_total_bytes
+            .is_some_and(|max_total_bytes| self.total_bytes > max_total_bytes)
+    }
+    /// Counts traffic that did not pass through the stream
+    pub(crate) fn count(&mut self, uploaded: usize, downloaded: usize) -> std::io::Result<()> {
+        self.upload.consume(uploaded);
+        self.download.consume(downloaded);
+        self.add_bytes(uploaded + downloaded)
+    }
+    fn add_bytes(&mut self, bytes: usize) -> std::io::Result<()> {
+        self.total_bytes += bytes as u64;
+        if self.cap_exceeded() {
+            return Err(std::io::Error::other(TRAFFIC_CAP_EXCEEDED));
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ShapedStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.download.poll_ready(cx));
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - filled;
+        self.download.consume(read);
+        Poll::Ready(self.add_bytes(read))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ShapedStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        ready!(self.upload.poll_ready(cx));
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.upload.consume(written);
+        Poll::Ready(self.add_bytes(written).map(|_| written))
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(RateLimit {
+            bytes_per_second: 1000,
+            burst_bytes: 500,
+        });
+        let now = bucket.last_refill;
+        bucket.consume(500, now);
+        assert_eq!(bucket.wait_time(now), Duration::ZERO);
+        bucket.consume(1000, now);
+        assert_eq!(bucket.wait_time(now), Duration::from_secs(1));
+        assert_eq!(
+            bucket.wait_time(now + Duration::from_millis(400)),
+            Duration::from_millis(600)
+        );
+        // Refills no further than the burst
+        assert_eq!(
+            bucket.wait_time(now + Duration::from_secs(60)),
+            Duration::ZERO
+        );
+        assert_eq!(bucket.tokens, 500.0);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn test_traffic_cap() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let mut shaped = ShapedStream::new(client, &TrafficLimits::new().with_max_total_bytes(12));
+        shaped.count(1, 1).unwrap();
+        shaped.write_all(b"hello").await.unwrap();
+        server.write_all(b"world").await.unwrap();
+        let mut buf = [0u8; 5];
+        shaped.read_exact(&mut buf).await.unwrap();
+        assert!(!shaped.cap_exceeded());
+        server.write_all(b"!").await.unwrap();
+        assert!(shaped.read(&mut buf).await.is_err());
+        assert!(shaped.cap_exceeded());
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt::Debug;
+
+use crate::socket_gateway::http_proxy::{ConnectionContext, Request};
+
+pub const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+/// Flags of traces started by the gateway, sampled
+const TRACE_FLAGS: &str = "01";
+
+/// Adds headers computed per connection to the request forwarded to the server
+pub trait RequestHook: Debug + Send + Sync {
+    fn apply(&self, request: &mut Request, context: &ConnectionContext);
+}
+
+/// Appends the client address to `X-Forwarded-For`
+#[derive(Debug)]
+pub struct ForwardedFor;
+
+impl RequestHook for ForwardedFor {
+    fn apply(&self, request: &mut Request, context: &ConnectionContext) {
+        let client_ip = context.peer_addr.ip().to_string();
+        let forwarded_for = match request.header(FORWARDED_FOR_HEADER) {
+            Some(forwarded_for) => format!("{}, {}", forwarded_for, client_ip),
+            None => client_ip,
+        };
+        request.set_header(FORWARDED_FOR_HEADER, &forwarded_for);
+    }
+}
+
+/// Continues the W3C trace of the client with the gateway as parent, or starts a new one
+#[derive(Debug)]
+pub struct TraceParent;
+
+impl RequestHook for TraceParent {
+    fn apply(&self, request: &mut Request, _context: &ConnectionContext) {
+        let traceparent = next_traceparent(request.header(TRACEPARENT_HEADER));
+        request.set_header(TRACEPARENT_HEADER, &traceparent);
+    }
+}
+
+/// Parses a version 00 `traceparent`, returns its trace id and flags
+fn parse_traceparent(traceparent: &str) -> Option<(&str, &str)> {
+    let is_hex = |s: &str| {
+        s.bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    match traceparent.trim().split('-').collect::<Vec<_>>().as_slice() {
+        ["00", trace_id, parent_id, flags]
+            if trace_id.len() == 32
+                && parent_id.len() == 16
+                && flags.len() == 2
+                && is_hex(trace_id)
+                && is_hex(parent_id)
+                && is_hex(flags)
+                && trace_id.bytes().any(|b| b != b'0') =>
+        {
+            Some((trace_id, flags))
+        }
+        _ => None,
+    }
+}
+
+fn next_traceparent(incoming: Option<&str>) -> String {
+    let span_id = uuid::Uuid::new_v4().simple().to_string();
+    let span_id = &span_id[..16];
+    match incoming.and_then(parse_traceparent) {
+        Some((trace_id, flags)) => format!("00-{}-{}-{}", trace_id, span_id, flags),
+        None => format!(
+            "00-{}-{}-{}",
+            uuid::Uuid::new_v4().simple(),
+            span_id,
+            TRACE_FLAGS
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_forwarding_headers() {
+        let context = ConnectionContext {
+            peer_addr: "10.0.0.2:5000".parse().unwrap(),
+        };
+        let mut request = Request::new(
+            "GET / HTTP/1.1\r\nX-Forwarded-For: 1.2.3.4\r\ntraceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01\r\n\r\n",
+        )
+        .unwrap();
+        ForwardedFor.apply(&mut request, &context);
+        TraceParent.apply(&mut request, &context);
+        assert_eq!(
+            request.header(FORWARDED_FOR_HEADER),
+            Some("1.2.3.4, 10.0.0.2")
+        );
+        let traceparent = request.header(TRACEPARENT_HEADER).unwrap();
+        assert!(traceparent.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+        assert!(!traceparent.contains("00f067aa0ba902b7"));
+
+        let mut request = Request::new("GET / HTTP/1.1\r\ntraceparent: bogus\r\n\r\n").unwrap();
+        TraceParent.apply(&mut request, &context);
+        assert!(parse_traceparent(request.header(TRACEPARENT_HEADER).unwrap()).is_some());
+    }
+}
@@ -1,15 +1,179 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::sync::CancellationToken;
 use tracing::warn;
 
+use crate::get_timestamp_ms;
+use crate::socket_gateway::access_log::{AccessLog, AccessLogEntry, parse_status};
+use crate::socket_gateway::bandwidth::{ShapedStream, TRAFFIC_CAP_EXCEEDED, TrafficLimits};
+use crate::socket_gateway::metrics::{ActiveConnection, Connections, ProxyDirection};
+use crate::socket_gateway::recording::{Record, RecordingStream, SessionRecorder};
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum Error {
     ParseError(&'static str),
     IoError(&'static str),
+    /// Client did not present valid credentials
+    Unauthorized(&'static str),
+    /// Client exceeded one of its limits
+    TooManyRequests(&'static str),
+    /// Client referred to something that does not exist (anymore)
+    NotFound(&'static str),
+    /// Server did not answer in time
+    Timeout(&'static str),
+    /// Client asked for something another client holds
+    Conflict(&'static str),
+    /// Connection carried all the traffic it may, unlike rate limits retrying does not help
+    TrafficCapExceeded(&'static str),
+}
+
+impl Error {
+    fn status_line(&self) -> &'static str {
+        match self {
+            Error::ParseError(_) => "400 Bad Request",
+            Error::IoError(_) => "502 Bad Gateway",
+            Error::Unauthorized(_) => "401 Unauthorized",
+            Error::TooManyRequests(_) => "429 Too Many Requests",
+            Error::NotFound(_) => "404 Not Found",
+            Error::Timeout(_) => "504 Gateway Timeout",
+            Error::Conflict(_) => "409 Conflict",
+            Error::TrafficCapExceeded(_) => "413 Content Too Large",
+        }
+    }
+    fn status_code(&self) -> u16 {
+        self.status_line()
+            .split(' ')
+            .next()
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(500)
+    }
+    pub fn message(&self) -> &'static str {
+        match self {
+            Error::ParseError(message)
+            | Error::IoError(message)
+            | Error::Unauthorized(message)
+            | Error::TooManyRequests(message)
+            | Error::NotFound(message)
+            | Error::Timeout(message)
+            | Error::Conflict(message)
+            | Error::TrafficCapExceeded(message) => message,
+        }
+    }
+}
+
+/// Answers a request that could not be proxied, the connection is closed afterwards
+async fn write_error_response(stream: &mut (impl AsyncWrite + Unpin), error: &Error) {
+    let message = error.message();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        error.status_line(),
+        message.len(),
+        message
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        warn!("Failed to write error response: {:?}", e);
+    }
+}
+
+/// Hop-by-hop headers of RFC 7230, they only apply to a single connection and are not forwarded
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Proxy-Connection",
+    "TE",
+    "Trailer",
+    "Upgrade",
+];
+
+/// What the gateway knows about a client besides its request
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    pub peer_addr: SocketAddr,
+}
+
+/// Length of the response start kept to read its status
+const RESPONSE_START_LENGTH: usize = 16;
+
+/// Server stream counting the bytes sent and received, it keeps the start of the response
+struct MeteredStream<S> {
+    inner: S,
+    read_bytes: u64,
+    written_bytes: u64,
+    response_start: Vec<u8>,
+}
+
+impl<S> MeteredStream<S> {
+    fn new(inner: S) -> Self {
+        Self {
+            inner,
+            read_bytes: 0,
+            written_bytes: 0,
+            response_start: Vec::with_capacity(RESPONSE_START_LENGTH),
+        }
+    }
 }
 
-async fn read_until_empty_line(
-    stream: &mut tokio::net::TcpStream,
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let read = &buf.filled()[filled..];
+            self.read_bytes += read.len() as u64;
+            let missing = RESPONSE_START_LENGTH.saturating_sub(self.response_start.len());
+            self.response_start
+                .extend_from_slice(&read[..missing.min(read.len())]);
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.written_bytes += written as u64;
+        }
+        poll
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> MeteredStream<ShapedStream<S>> {
+    /// Counts traffic exchanged with the server before it was wrapped, e.g. the handshake of
+    /// the upgrade request
+    fn count_handshake(&mut self, written: usize, read: usize) -> std::io::Result<()> {
+        self.written_bytes += written as u64;
+        self.read_bytes += read as u64;
+        self.inner.count(written, read)
+    }
+}
+
+/// Any stream a client can be connected through, e.g. plain TCP or TLS
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> ClientStream for T {}
+
+pub(crate) async fn read_until_empty_line(
+    stream: &mut (impl AsyncRead + Unpin),
     data: &mut String,
 ) -> Result<(), Error> {
     data.clear();
@@ -27,7 +191,7 @@ async fn read_until_empty_line(
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request {
     pub method: String,
     pub path: String,
@@ -59,6 +223,89 @@ impl Request {
         Err(Error::ParseError("Missing empty line"))
     }
 
+    /// Returns the value of the first header named `key`, case insensitive
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn remove_header(&mut self, key: &str) {
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+
+    /// Whether a body follows the head, the proxy then can't wait for the response before
+    /// forwarding the rest of the request
+    pub fn has_body(&self) -> bool {
+        self.header("Transfer-Encoding").is_some()
+            || self
+                .header("Content-Length")
+                .is_some_and(|length| length.trim() != "0")
+    }
+
+    /// Replaces all headers named `key` with a single one
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.remove_header(key);
+        self.headers.push((key.to_string(), value.to_string()));
+    }
+
+    /// Removes hop-by-hop headers and the headers listed in `Connection`.
+    /// Protocol upgrades are kept as the proxy hands the upgraded connection through, and
+    /// `Transfer-Encoding` is kept as the body is forwarded as is
+    pub fn strip_hop_by_hop_headers(&mut self) {
+        let connection_tokens = self
+            .headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, v)| v.split(','))
+            .map(|token| token.trim().to_string())
+            .filter(|token| !token.is_empty())
+            .collect::<Vec<_>>();
+        let upgrade = self.header("Upgrade").map(str::to_string).filter(|_| {
+            connection_tokens
+                .iter()
+                .any(|token| token.eq_ignore_ascii_case("upgrade"))
+        });
+        let is_hop_by_hop = |key: &str| {
+            HOP_BY_HOP_HEADERS
+                .into_iter()
+                .chain(connection_tokens.iter().map(String::as_str))
+                .any(|header| key.eq_ignore_ascii_case(header))
+        };
+        self.headers.retain(|(k, _)| !is_hop_by_hop(k));
+        if let Some(upgrade) = upgrade {
+            self.headers
+                .push(("Connection".to_string(), "Upgrade".to_string()));
+            self.headers.push(("Upgrade".to_string(), upgrade));
+        }
+    }
+
+    /// Returns the value of the query parameter `key`, values are not percent decoded
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        let (_, query) = self.path.split_once('?')?;
+        query
+            .split('&')
+            .filter_map(|param| param.split_once('='))
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+
+    pub fn remove_query_param(&mut self, key: &str) {
+        if let Some((path, query)) = self.path.split_once('?') {
+            let query = query
+                .split('&')
+                .filter(|param| param.split_once('=').map_or(*param, |(k, _)| k) != key)
+                .collect::<Vec<_>>()
+                .join("&");
+            self.path = if query.is_empty() {
+                path.to_string()
+            } else {
+                format!("{}?{}", path, query)
+            };
+        }
+    }
+
     fn parse_request_line(line: &str) -> Result<(&str, &str, &str), Error> {
         let mut parts = line.splitn(3, " ");
         let method = parts.next().ok_or(Error::ParseError("Missing method"))?;
@@ -74,7 +321,7 @@ impl Request {
         Ok((key.trim(), value.trim()))
     }
 
-    async fn write_arr(line: &[&str], stream: &mut tokio::net::TcpStream) -> Result<(), Error> {
+    async fn write_arr(line: &[&str], stream: &mut (impl AsyncWrite + Unpin)) -> Result<(), Error> {
         for &l in line {
             stream
                 .write_all(l.as_bytes())
@@ -83,7 +330,10 @@ impl Request {
         }
         Ok(())
     }
-    async fn write_request_line(&self, stream: &mut tokio::net::TcpStream) -> Result<(), Error> {
+    async fn write_request_line(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<(), Error> {
         Self::write_arr(
             &[&self.method, " ", &self.path, " ", &self.version, "\r\n"],
             stream,
@@ -94,13 +344,21 @@ impl Request {
     async fn write_header_line(
         key: &str,
         value: &str,
-        stream: &mut tokio::net::TcpStream,
+        stream: &mut (impl AsyncWrite + Unpin),
     ) -> Result<(), Error> {
         Self::write_arr(&[key, ": ", value, "\r\n"], stream).await?;
         Ok(())
     }
-    async fn write_to_stream(self, stream: &mut tokio::net::TcpStream) -> Result<(), Error> {
-        Self::write_request_line(&self, stream).await?;
+    /// Bytes written by `write_to_stream`
+    fn head_len(&self) -> usize {
+        self.method.len() + self.path.len() + self.version.len() + 4 + headers_len(&self.headers)
+    }
+
+    pub async fn write_to_stream(
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<(), Error> {
+        Self::write_request_line(self, stream).await?;
         for (key, value) in &self.headers {
             Self::write_header_line(key, value, stream).await?;
         }
@@ -109,16 +367,89 @@ impl Request {
     }
 }
 
+/// Bytes of the header lines and the empty line ending them
+fn headers_len(headers: &[(String, String)]) -> usize {
+    headers
+        .iter()
+        .map(|(key, value)| key.len() + value.len() + 4)
+        .sum::<usize>()
+        + 2
+}
+
+/// Head of the server response, only parsed when the proxy has to modify it
+#[derive(Debug)]
+pub struct Response {
+    pub version: String,
+    pub status: String,
+    pub headers: Vec<(String, String)>,
+}
+impl Response {
+    pub fn new(data: &str) -> Result<Self, Error> {
+        let mut lines = data.split("\r\n");
+
+        let status_line = lines
+            .next()
+            .ok_or(Error::ParseError("Missing status line"))?;
+        let (version, status) = status_line
+            .split_once(' ')
+            .ok_or(Error::ParseError("Malformed status line"))?;
+        let mut result = Self {
+            version: version.to_string(),
+            status: status.to_string(),
+            headers: Vec::new(),
+        };
+        for line in lines {
+            if line.is_empty() {
+                return Ok(result);
+            }
+            let (key, value) = Request::parse_header_line(line)?;
+            result.headers.push((key.to_string(), value.to_string()));
+        }
+        Err(Error::ParseError("Missing empty line"))
+    }
+
+    /// Bytes of the response head as read from the server
+    fn head_len(&self) -> usize {
+        self.version.len() + self.status.len() + 3 + headers_len(&self.headers)
+    }
+
+    pub fn status_code(&self) -> Option<u16> {
+        self.status.split(' ').next()?.parse().ok()
+    }
+
+    async fn write_to_stream(self, stream: &mut (impl AsyncWrite + Unpin)) -> Result<(), Error> {
+        Request::write_arr(&[&self.version, " ", &self.status, "\r\n"], stream).await?;
+        for (key, value) in &self.headers {
+            Request::write_header_line(key, value, stream).await?;
+        }
+        Request::write_arr(&["\r\n"], stream).await?;
+        Ok(())
+    }
+}
+
 pub struct HttpProxyInstance<M: ServerConnectionManagerTrait> {
     pub request: Request,
     pub server: tokio::net::TcpStream,
     pub manager: M,
+    /// Headers added to the server response, e.g. to hand out a session token
+    pub response_headers: Vec<(String, String)>,
+    /// Instance served by the connection, for the access log
+    pub instance_id: Option<String>,
+    /// Set when the request was already sent and the server answered with this response head,
+    /// e.g. to retry another server when one did not answer in time
+    pub response: Option<Response>,
+    /// Rate limits and byte cap of the connection, unlimited by default
+    pub traffic_limits: TrafficLimits,
+    /// Records the websocket messages of the connection when set
+    pub recorder: Option<SessionRecorder>,
 }
 
+/// Shared by all connections of a gateway, which are set up concurrently
 pub trait HttpProxyConfigTrait<M: ServerConnectionManagerTrait> {
     fn new_connection(
-        &mut self,
+        &self,
         request: Request,
+        context: &ConnectionContext,
     ) -> impl std::future::Future<Output = Result<HttpProxyInstance<M>, Error>> + Send;
 }
 
@@ -130,37 +461,160 @@ pub trait ServerConnectionManagerTrait: Send + Sync {
     ) -> impl std::future::Future<Output = Result<(), Error>> + Send;
 }
 
+/// Reads the request of the client and connects it to a server, then proxies the connection
+/// on its own task. Counts as running until `active_connection` is dropped with the connection
 pub async fn start_http_proxy_connection<
     C: HttpProxyConfigTrait<M>,
     M: ServerConnectionManagerTrait + 'static,
 >(
-    proxy_config: &mut C,
-    mut client: tokio::net::TcpStream,
+    proxy_config: &C,
+    mut client: impl ClientStream,
+    context: &ConnectionContext,
+    connections: &Connections,
+    active_connection: ActiveConnection,
+    force_close: &CancellationToken,
+    access_log: Option<&AccessLog>,
 ) -> Result<(), Error> {
+    let start_timestamp_ms = get_timestamp_ms().timestamp_ms;
+    let started = tokio::time::Instant::now();
     let mut data = String::with_capacity(1024);
     read_until_empty_line(&mut client, &mut data).await?;
-    let request = Request::new(&data)?;
-    let instance = proxy_config.new_connection(request).await?;
+    let mut logged_request = (None, None);
+    let instance = match Request::new(&data) {
+        Ok(request) => {
+            // The query may hold credentials, so only the path the client requested is logged
+            let path = request
+                .path
+                .split('?')
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            logged_request = (Some(request.method.clone()), Some(path));
+            proxy_config.new_connection(request, context).await
+        }
+        Err(e) => Err(e),
+    };
+    let instance = match instance {
+        Ok(instance) => instance,
+        Err(e) => {
+            write_error_response(&mut client, &e).await;
+            if let Some(access_log) = access_log {
+                let (method, path) = logged_request;
+                access_log.write(&AccessLogEntry {
+                    start_timestamp_ms,
+                    end_timestamp_ms: get_timestamp_ms().timestamp_ms,
+                    duration_ms: started.elapsed().as_millis() as u64,
+                    client_addr: context.peer_addr,
+                    method,
+                    path,
+                    upstream: None,
+                    instance_id: None,
+                    status: Some(e.status_code()),
+                    client_to_server_bytes: 0,
+                    server_to_client_bytes: 0,
+                    close_reason: e.message().to_string(),
+                });
+            }
+            return Err(e);
+        }
+    };
+    let connections = connections.clone();
+    let force_close = force_close.clone();
+    let access_log = access_log.cloned();
+    let client_addr = context.peer_addr;
     tokio::spawn(async move {
         let HttpProxyInstance {
             request,
-            mut server,
+            server,
             mut manager,
+            response_headers,
+            instance_id,
+            response,
+            traffic_limits,
+            recorder,
         } = instance;
-        let proxy_result = async {
-            manager.on_open().await?;
-            request.write_to_stream(&mut server).await?;
-            tokio::io::copy_bidirectional(&mut client, &mut server)
-                .await
-                .map_err(|_| Error::IoError("Failed while sending data to/from server"))?;
-            Ok::<(), Error>(())
+        let response_status = response.as_ref().and_then(Response::status_code);
+        let upstream = server.peer_addr().ok().map(|addr| addr.to_string());
+        let (method, path) = logged_request;
+        let response_pending = response.is_none() && response_headers.is_empty();
+        if let Some(recorder) = &recorder {
+            recorder.write(&Record::Open {
+                timestamp_ms: start_timestamp_ms,
+                path: request.path.clone(),
+                instance_id: instance_id.clone(),
+            });
+        }
+        let mut server = MeteredStream::new(ShapedStream::new(server, &traffic_limits));
+        let proxy_result = tokio::select! {
+            result = async {
+                // The handshake happened while setting up the connection, it counts all the same
+                if let Some(response) = &response {
+                    server
+                        .count_handshake(request.head_len(), response.head_len())
+                        .map_err(|_| Error::TrafficCapExceeded(TRAFFIC_CAP_EXCEEDED))?;
+                }
+                manager.on_open().await?;
+                if let Some(mut response) = response {
+                    response.headers.extend(response_headers);
+                    response.write_to_stream(&mut client).await?;
+                } else {
+                    request.write_to_stream(&mut server).await?;
+                    if !response_headers.is_empty() {
+                        let mut data = String::with_capacity(1024);
+                        read_until_empty_line(&mut server, &mut data).await?;
+                        let mut response = Response::new(&data)?;
+                        response.headers.extend(response_headers);
+                        response.write_to_stream(&mut client).await?;
+                    }
+                }
+                let copied = match recorder {
+                    Some(recorder) => {
+                        let mut server = RecordingStream::new(&mut server, recorder, response_pending);
+                        tokio::io::copy_bidirectional(&mut client, &mut server).await
+                    }
+                    None => tokio::io::copy_bidirectional(&mut client, &mut server).await,
+                };
+                copied.map_err(|_| Error::IoError("Failed while sending data to/from server"))?;
+                Ok::<(), Error>(())
+            } => result,
+            _ = force_close.cancelled() => Err(Error::IoError("Connection closed on shutdown")),
+        };
+        let proxy_result = if server.inner.cap_exceeded() {
+            Err(Error::TrafficCapExceeded(TRAFFIC_CAP_EXCEEDED))
+        } else {
+            proxy_result
+        };
+
+        connections.message(
+            ProxyDirection::ClientToServer,
+            server.written_bytes as usize,
+        );
+        connections.message(ProxyDirection::ServerToClient, server.read_bytes as usize);
+        if let Some(access_log) = access_log {
+            access_log.write(&AccessLogEntry {
+                start_timestamp_ms,
+                end_timestamp_ms: get_timestamp_ms().timestamp_ms,
+                duration_ms: started.elapsed().as_millis() as u64,
+                client_addr,
+                method,
+                path,
+                upstream,
+                instance_id,
+                status: response_status.or_else(|| parse_status(&server.response_start)),
+                client_to_server_bytes: server.written_bytes,
+                server_to_client_bytes: server.read_bytes,
+                close_reason: match &proxy_result {
+                    Ok(()) => "Connection closed".to_string(),
+                    Err(e) => e.message().to_string(),
+                },
+            });
         }
-        .await;
 
         // This executes when the connection terminates
         if let Err(e) = manager.on_close(proxy_result).await {
             warn!("Error in on_close: {:?}", e);
         }
+        drop(active_connection);
         Ok::<(), Error>(())
     });
     Ok(())
@@ -183,5 +637,41 @@ mod tests {
         assert_eq!(request.headers[0].1, "example.com");
         assert_eq!(request.headers[1].0, "Content-Length");
         assert_eq!(request.headers[1].1, "10");
+        assert_eq!(request.head_len(), request_str.len());
+    }
+
+    #[test]
+    fn test_query_params() {
+        let request_str = "GET /json?api_key=abc&foo=bar HTTP/1.1\r\nX-Api-Key: def\r\n\r\n";
+        #[allow(clippy::unwrap_used)]
+        let mut request = Request::new(request_str).unwrap();
+        assert_eq!(request.query_param("api_key"), Some("abc"));
+        assert_eq!(request.query_param("missing"), None);
+        assert_eq!(request.header("x-api-key"), Some("def"));
+        request.remove_query_param("api_key");
+        assert_eq!(request.path, "/json?foo=bar");
+        request.remove_query_param("foo");
+        assert_eq!(request.path, "/json");
+        request.remove_header("X-API-KEY");
+        assert!(request.headers.is_empty());
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_strip_hop_by_hop_headers() {
+        let request_str = "GET / HTTP/1.1\r\nConnection: keep-alive, Upgrade, X-Secret\r\nUpgrade: websocket\r\nKeep-Alive: timeout=5\r\nX-Secret: a\r\nTransfer-Encoding: chunked\r\nSec-WebSocket-Key: b\r\n\r\n";
+        let mut request = Request::new(request_str).unwrap();
+        request.strip_hop_by_hop_headers();
+        assert_eq!(request.header("Connection"), Some("Upgrade"));
+        assert_eq!(request.header("Upgrade"), Some("websocket"));
+        assert_eq!(request.header("Keep-Alive"), None);
+        assert_eq!(request.header("X-Secret"), None);
+        assert_eq!(request.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(request.header("Sec-WebSocket-Key"), Some("b"));
+
+        // Upgrade without Connection: upgrade is not an upgrade request
+        let mut request = Request::new("GET / HTTP/1.1\r\nUpgrade: h2c\r\n\r\n").unwrap();
+        request.strip_hop_by_hop_headers();
+        assert!(request.headers.is_empty());
     }
 }
@@ -1,7 +1,6 @@
-use std::sync::Arc;
 use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
 
-use tokio::sync::Mutex;
 use tracing::warn;
 
 pub enum ProxyDirection {
@@ -40,27 +39,39 @@ pub struct Metrics {
     pub server_to_client_bytes: u64,
 }
 
-impl Drop for Connections {
+/// Counts as a running connection until dropped
+pub struct ActiveConnection {
+    state: Arc<Mutex<ProxyState>>,
+}
+
+impl Drop for ActiveConnection {
     fn drop(&mut self) {
-        let state = self.state.clone();
-        tokio::spawn(async move {
-            let mut state = state.lock().await;
-            *state = match &*state {
-                ProxyState::Connected(1) => ProxyState::Disconnected(tokio::time::Instant::now()),
-                ProxyState::Connected(num) if *num > 1 => ProxyState::Connected(num - 1),
-                ProxyState::Disconnected(_)
-                | ProxyState::Connected(_)
-                | ProxyState::NoConnectionEstablished => {
-                    warn!("Invalid state");
-                    ProxyState::Disconnected(tokio::time::Instant::now())
-                }
-            };
-        });
+        // Updated synchronously, so the count is correct even while the runtime shuts down
+        let Ok(mut state) = self.state.lock() else {
+            warn!("Connection state lock poisoned");
+            return;
+        };
+        *state = match &*state {
+            ProxyState::Connected(1) => ProxyState::Disconnected(tokio::time::Instant::now()),
+            ProxyState::Connected(num) if *num > 1 => ProxyState::Connected(num - 1),
+            ProxyState::Disconnected(_)
+            | ProxyState::Connected(_)
+            | ProxyState::NoConnectionEstablished => {
+                warn!("Invalid state");
+                ProxyState::Disconnected(tokio::time::Instant::now())
+            }
+        };
+    }
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Connections {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Connections {
             state: Arc::new(Mutex::new(ProxyState::NoConnectionEstablished)),
             num_connections: Arc::new(AtomicI64::new(0)),
@@ -79,27 +90,34 @@ impl Connections {
                 .fetch_add(bytes as i64, Ordering::Relaxed),
         };
     }
-    pub(crate) async fn new_connection(&self) -> Self {
-        let mut state = self.state.lock().await;
+    pub fn new_connection(&self) -> ActiveConnection {
         self.num_connections.fetch_add(1, Ordering::Relaxed);
-        let connections = Connections {
+        if let Ok(mut state) = self.state.lock() {
+            *state = match &*state {
+                ProxyState::Connected(num) => ProxyState::Connected(num + 1),
+                ProxyState::Disconnected(_) | ProxyState::NoConnectionEstablished => {
+                    ProxyState::Connected(1)
+                }
+            };
+        }
+        ActiveConnection {
             state: self.state.clone(),
-            num_connections: self.num_connections.clone(),
-            client_to_server_bytes: self.client_to_server_bytes.clone(),
-            server_to_client_bytes: self.server_to_client_bytes.clone(),
-        };
-        *state = match &*state {
-            ProxyState::Connected(num) => ProxyState::Connected(num + 1),
-            ProxyState::Disconnected(_) | ProxyState::NoConnectionEstablished => {
-                ProxyState::Connected(1)
-            }
-        };
-        connections
+        }
+    }
+    pub fn active_connections(&self) -> u64 {
+        self.state
+            .lock()
+            .map(|state| state.active_connections())
+            .unwrap_or_default()
     }
-    pub async fn metrics(&self) -> Metrics {
-        let state = self.state.lock().await;
+    pub fn metrics(&self) -> Metrics {
+        let state = self
+            .state
+            .lock()
+            .map(|state| state.clone())
+            .unwrap_or(ProxyState::NoConnectionEstablished);
         Metrics {
-            state: (*state).clone(),
+            state,
             num_connections: self.num_connections.load(Ordering::Relaxed) as u64,
             client_to_server_bytes: self.client_to_server_bytes.load(Ordering::Relaxed) as u64,
             server_to_client_bytes: self.server_to_client_bytes.load(Ordering::Relaxed) as u64,
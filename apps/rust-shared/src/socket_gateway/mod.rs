@@ -1,2 +1,11 @@
+pub mod access_log;
+pub mod bandwidth;
+pub mod forwarding;
 pub mod http_proxy;
+pub mod metrics;
+pub mod proxy_protocol;
+pub mod recording;
 pub mod simple_gateway;
+pub mod tls;
+pub mod upstream;
+pub mod websocket;
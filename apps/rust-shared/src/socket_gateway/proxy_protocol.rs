@@ -0,0 +1,172 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::socket_gateway::http_proxy::Error;
+
+/// Longest v1 header allowed by the spec, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Reads the HAProxy PROXY protocol (v1 or v2) header a load balancer sends before the
+/// client's data, see https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+/// Returns the address of the original client, or None when the load balancer did not
+/// relay one, e.g. for its own health checks. Nothing past the header is read
+pub async fn read_proxy_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<SocketAddr>, Error> {
+    let first = stream
+        .read_u8()
+        .await
+        .map_err(|_| Error::IoError("Failed to read PROXY header"))?;
+    match first {
+        b'P' => read_v1(stream).await,
+        b'\r' => read_v2(stream).await,
+        _ => Err(Error::ParseError("Missing PROXY header")),
+    }
+}
+
+async fn read_v1(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, Error> {
+    let mut line = vec![b'P'];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(Error::ParseError("PROXY header too long"));
+        }
+        line.push(
+            stream
+                .read_u8()
+                .await
+                .map_err(|_| Error::IoError("Failed to read PROXY header"))?,
+        );
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| Error::ParseError("Invalid PROXY header"))?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, Error> {
+    let invalid = || Error::ParseError("Invalid PROXY header");
+    match line.split(' ').collect::<Vec<_>>().as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            protocol @ ("TCP4" | "TCP6"),
+            source,
+            _destination,
+            source_port,
+            _,
+        ] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| invalid())?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid());
+            }
+            let port = source_port.parse::<u16>().map_err(|_| invalid())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> Result<Option<SocketAddr>, Error> {
+    // Signature without its first byte, version and command, family, length
+    let mut header = [0u8; 15];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|_| Error::IoError("Failed to read PROXY header"))?;
+    if header[..11] != V2_SIGNATURE[1..] {
+        return Err(Error::ParseError("Invalid PROXY header"));
+    }
+    let length = u16::from_be_bytes([header[13], header[14]]) as usize;
+    let mut addresses = vec![0u8; length];
+    stream
+        .read_exact(&mut addresses)
+        .await
+        .map_err(|_| Error::IoError("Failed to read PROXY header"))?;
+    parse_v2(header[11], header[12], &addresses)
+}
+
+fn parse_v2(
+    version_command: u8,
+    family: u8,
+    addresses: &[u8],
+) -> Result<Option<SocketAddr>, Error> {
+    if version_command >> 4 != 2 {
+        return Err(Error::ParseError("Unsupported PROXY version"));
+    }
+    match version_command & 0x0F {
+        // LOCAL, connection of the load balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(Error::ParseError("Unsupported PROXY command")),
+    }
+    // Upper nibble is the address family, the lower one the transport
+    match (family >> 4, addresses) {
+        (0x1, [a, b, c, d, _, _, _, _, p0, p1, ..]) => Ok(Some(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(*a, *b, *c, *d)),
+            u16::from_be_bytes([*p0, *p1]),
+        ))),
+        (0x2, addresses) if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(
+                IpAddr::V6(Ipv6Addr::from(ip)),
+                u16::from_be_bytes([addresses[32], addresses[33]]),
+            )))
+        }
+        (0x1 | 0x2, _) => Err(Error::ParseError("Truncated PROXY addresses")),
+        // Unix sockets and unspecified families carry no client ip
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn test_v1() {
+        let mut data: &[u8] = b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        let addr = read_proxy_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(data, b"GET / HTTP/1.1\r\n");
+
+        let mut data: &[u8] = b"PROXY TCP6 ::1 ::1 1000 443\r\n";
+        let addr = read_proxy_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("[::1]:1000".parse().unwrap()));
+
+        let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut data).await.unwrap(), None);
+
+        let mut data: &[u8] = b"PROXY TCP6 192.168.0.1 10.0.0.1 56324 443\r\n";
+        assert!(read_proxy_header(&mut data).await.is_err());
+        let mut data: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_proxy_header(&mut data).await.is_err());
+    }
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn test_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([
+            0x21, 0x11, 0, 12, 192, 168, 0, 1, 10, 0, 0, 1, 0xDC, 0x04, 1, 187,
+        ]);
+        header.extend(b"GET");
+        let mut data = header.as_slice();
+        let addr = read_proxy_header(&mut data).await.unwrap();
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        assert_eq!(data, b"GET");
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(
+            read_proxy_header(&mut header.as_slice()).await.unwrap(),
+            None
+        );
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 4, 192, 168, 0, 1]);
+        assert!(read_proxy_header(&mut header.as_slice()).await.is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+use crate::get_timestamp_ms;
+use crate::socket_gateway::access_log::{parse_status, write_lines};
+use crate::socket_gateway::http_proxy::Error;
+use crate::socket_gateway::websocket::{MessageReader, OPCODE_TEXT};
+
+/// Longest response head looked for before recording gives up on a connection
+const MAX_HEAD_LENGTH: usize = 64 * 1024;
+/// Records queued for the writer task before new ones are dropped
+const QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// One line of a recording
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    /// First line, the request as it was forwarded to the server
+    Open {
+        timestamp_ms: u64,
+        path: String,
+        instance_id: Option<String>,
+    },
+    /// A complete websocket message, text messages are kept as text, others as base64
+    Message {
+        timestamp_ms: u64,
+        direction: Direction,
+        opcode: u8,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none", with = "base64_data")]
+        data: Option<Vec<u8>>,
+    },
+    /// The direction could not be parsed as websocket frames, it is not recorded any further
+    Error {
+        timestamp_ms: u64,
+        direction: Direction,
+        message: String,
+    },
+}
+
+impl Record {
+    pub fn message(direction: Direction, opcode: u8, payload: Vec<u8>) -> Self {
+        let (text, data) = match (opcode, String::from_utf8(payload)) {
+            (OPCODE_TEXT, Ok(text)) => (Some(text), None),
+            (_, Ok(text)) => (None, Some(text.into_bytes())),
+            (_, Err(e)) => (None, Some(e.into_bytes())),
+        };
+        Record::Message {
+            timestamp_ms: get_timestamp_ms().timestamp_ms,
+            direction,
+            opcode,
+            text,
+            data,
+        }
+    }
+}
+
+/// Binary payloads are written as base64, recordings with payloads as byte arrays are still read
+mod base64_data {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Data {
+        Base64(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(
+        data: &Option<Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match data {
+            Some(data) => serializer.serialize_some(&STANDARD.encode(data)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Vec<u8>>, D::Error> {
+        match Option::<Data>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(Data::Bytes(bytes)) => Ok(Some(bytes)),
+            Some(Data::Base64(data)) => STANDARD
+                .decode(data)
+                .map(Some)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Writes the websocket messages of a proxied connection as JSON lines. Lines are written by a
+/// task of their own, so the connection never waits on the file
+#[derive(Clone)]
+pub struct SessionRecorder(mpsc::Sender<Vec<u8>>);
+
+impl SessionRecorder {
+    /// Must be called from within a tokio runtime
+    pub fn create(path: &Path) -> anyhow::Result<Self> {
+        Self::create_with_writer(path).map(|(recorder, _)| recorder)
+    }
+
+    /// Also returns the writer task, it finishes once every clone of the recorder is dropped
+    fn create_with_writer(path: &Path) -> anyhow::Result<(Self, JoinHandle<()>)> {
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .context("Failed to create recording")?;
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let writer = tokio::spawn(write_lines(
+            receiver,
+            Box::new(tokio::fs::File::from_std(file)),
+        ));
+        Ok((SessionRecorder(sender), writer))
+    }
+
+    pub fn write(&self, record: &Record) {
+        let Ok(mut line) = serde_json::to_vec(record) else {
+            warn!("Failed to serialize record");
+            return;
+        };
+        line.push(b'\n');
+        if let Err(e) = self.0.try_send(line) {
+            warn!("Dropped record: {}", e);
+        }
+    }
+}
+
+/// Recording state of one direction of the connection
+struct RecordedDirection {
+    direction: Direction,
+    /// Set while the response head still passes through the stream
+    head: Option<Vec<u8>>,
+    reader: MessageReader,
+    stopped: bool,
+}
+
+impl RecordedDirection {
+    fn new(direction: Direction, head_pending: bool) -> Self {
+        Self {
+            direction,
+            head: head_pending.then(Vec::new),
+            reader: MessageReader::new(),
+            stopped: false,
+        }
+    }
+
+    /// Fails on messages too large to be reassembled, any other data that is not websocket
+    /// frames only stops the recording
+    fn feed(&mut self, data: &[u8], recorder: &SessionRecorder) -> std::io::Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        let frames = match &mut self.head {
+            Some(head) => {
+                head.extend_from_slice(data);
+                let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") else {
+                    self.stopped = head.len() > MAX_HEAD_LENGTH;
+                    return Ok(());
+                };
+                let frames = head.split_off(end + 4);
+                // Only upgraded connections carry websocket frames
+                self.stopped = parse_status(head) != Some(101);
+                self.head = None;
+                frames
+            }
+            None => data.to_vec(),
+        };
+        if self.stopped {
+            return Ok(());
+        }
+        match self.reader.feed(&frames) {
+            Ok(messages) => {
+                for (opcode, payload) in messages {
+                    recorder.write(&Record::message(self.direction, opcode, payload));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                recorder.write(&Record::Error {
+                    timestamp_ms: get_timestamp_ms().timestamp_ms,
+                    direction: self.direction,
+                    message: e.message().to_string(),
+                });
+                self.stopped = true;
+                match e {
+                    Error::TrafficCapExceeded(message) => Err(std::io::Error::other(message)),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Server stream recording the websocket messages written to and read from it
+pub(crate) struct RecordingStream<S> {
+    inner: S,
+    recorder: SessionRecorder,
+    client_to_server: RecordedDirection,
+    server_to_client: RecordedDirection,
+}
+
+impl<S> RecordingStream<S> {
+    /// `response_pending` when the response head was not read from the server yet
+    pub(crate) fn new(inner: S, recorder: SessionRecorder, response_pending: bool) -> Self {
+        Self {
+            inner,
+            recorder,
+            client_to_server: RecordedDirection::new(Direction::ClientToServer, false),
+            server_to_client: RecordedDirection::new(Direction::ServerToClient, response_pending),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let this = &mut *self;
+            this.server_to_client
+                .feed(&buf.filled()[filled..], &this.recorder)?;
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            let this = &mut *self;
+            this.client_to_server
+                .feed(&buf[..written], &this.recorder)?;
+        }
+        poll
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads a recording written by a `SessionRecorder`
+pub fn read_recording(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let content = std::fs::read_to_string(path).context("Failed to read recording")?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(line_number, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("Invalid record on line {}", line_number + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket_gateway::websocket::Frame;
+
+    #[tokio::test]
+    #[allow(clippy::unwrap_used)]
+    async fn test_recording() {
+        let path = std::env::temp_dir().join(format!("recording-{}.jsonl", uuid::Uuid::new_v4()));
+        let (recorder, writer) = SessionRecorder::create_with_writer(&path).unwrap();
+        let mut server_to_client = RecordedDirection::new(Direction::ServerToClient, true);
+        let mut data = b"HTTP/1.1 101 Switching Protocols\r\n\r\n".to_vec();
+        data.extend(
+            Frame {
+                fin: true,
+                opcode: OPCODE_TEXT,
+                payload: br#"{"id":1}"#.to_vec(),
+            }
+            .encode(None),
+        );
+        server_to_client.feed(&data[..20], &recorder).unwrap();
+        server_to_client.feed(&data[20..], &recorder).unwrap();
+        let mut client_to_server = RecordedDirection::new(Direction::ClientToServer, false);
+        client_to_server
+            .feed(
+                &Frame {
+                    fin: true,
+                    opcode: 0x2,
+                    payload: vec![0xFF, 1],
+                }
+                .encode(Some([9, 9, 9, 9])),
+                &recorder,
+            )
+            .unwrap();
+        drop(recorder);
+        writer.await.unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.contains(r#""data":"/wE=""#));
+        let records = read_recording(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(matches!(
+            &records[..],
+            [
+                Record::Message {
+                    direction: Direction::ServerToClient,
+                    text: Some(text),
+                    ..
+                },
+                Record::Message {
+                    direction: Direction::ClientToServer,
+                    opcode: 0x2,
+                    data: Some(data),
+                    ..
+                },
+            ] if text == r#"{"id":1}"# && data == &vec![0xFF, 1]
+        ));
+
+        // Recordings written before payloads were base64 encoded
+        let record: Record = serde_json::from_str(
+            r#"{"type":"message","timestamp_ms":1,"direction":"client_to_server","opcode":2,"data":[255,1]}"#,
+        )
+        .unwrap();
+        assert!(
+            matches!(record, Record::Message { data: Some(data), .. } if data == vec![0xFF, 1])
+        );
+    }
+}
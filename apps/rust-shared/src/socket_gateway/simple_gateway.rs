@@ -1,11 +1,22 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
 
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
+use crate::socket_gateway::access_log::AccessLog;
+use crate::socket_gateway::bandwidth::TrafficLimits;
+use crate::socket_gateway::forwarding::RequestHook;
+use crate::socket_gateway::metrics::{ActiveConnection, Connections};
+use crate::socket_gateway::proxy_protocol::read_proxy_header;
+use crate::socket_gateway::upstream::{UpstreamLease, UpstreamPool};
+
 use crate::socket_gateway::http_proxy::{
-    Error, HttpProxyConfigTrait, HttpProxyInstance, Request, ServerConnectionManagerTrait,
-    start_http_proxy_connection,
+    ConnectionContext, Error, HttpProxyConfigTrait, HttpProxyInstance, Request, Response,
+    ServerConnectionManagerTrait, read_until_empty_line, start_http_proxy_connection,
 };
 
 #[derive(Debug, Clone)]
@@ -20,6 +31,11 @@ pub enum PathOverride {
     /// e.g :
     /// - "/REPLACE/ROUTE" and "/USER/ROUTE" = "/REPLACE/ROUTE"
     Replace(String),
+    /// Replaces the path only if user path is "/", other paths are kept
+    /// e.g :
+    /// - "/REPLACE/ROUTE" and "/" = "/REPLACE/ROUTE"
+    /// - "/REPLACE/ROUTE" and "/USER/ROUTE" = "/USER/ROUTE"
+    ReplaceRoot(String),
     /// Adds suffix to the path
     /// If user path is "/" it is not added before
     /// e.g :
@@ -28,22 +44,83 @@ pub enum PathOverride {
     Append(String),
 }
 
+/// Limits on how long an upstream may take, unset ones wait indefinitely
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UpstreamTimeouts {
+    pub connect: Option<Duration>,
+    /// From the request being sent to the first byte of the response
+    pub first_byte: Option<Duration>,
+    /// From the request being sent to the end of the response head, e.g. a websocket upgrade
+    pub handshake: Option<Duration>,
+}
+
+/// Server address and path override of a gateway which can be rebound while it runs, e.g.
+/// when the server behind it restarted on another port. Requests get the address as Host
+#[derive(Debug, Clone)]
+pub struct GatewayTarget(Arc<RwLock<(String, PathOverride)>>);
+
+impl GatewayTarget {
+    pub fn new(server_addr: &str, path_override: PathOverride) -> Self {
+        Self(Arc::new(RwLock::new((
+            server_addr.to_string(),
+            path_override,
+        ))))
+    }
+    pub fn rebind(&self, server_addr: &str, path_override: PathOverride) {
+        if let Ok(mut target) = self.0.write() {
+            *target = (server_addr.to_string(), path_override);
+        }
+    }
+    pub fn get(&self) -> Option<(String, PathOverride)> {
+        self.0.read().ok().map(|target| target.clone())
+    }
+}
+
 #[derive(Debug)]
 pub struct HttpProxyConfig {
     pub overide_headers: HashMap<String, String>,
     pub path_override: PathOverride,
-    pub server_addr: String,
-    pub connection_count: usize,
+    pub upstreams: UpstreamPool,
+    pub connection_count: AtomicUsize,
+    /// Applied after the header overrides
+    pub request_hooks: Vec<Arc<dyn RequestHook>>,
+    pub timeouts: UpstreamTimeouts,
+    /// Applied to every connection, connections sharing a `SharedRateLimiter` share its rate
+    pub traffic_limits: TrafficLimits,
+    /// Replaces the upstreams and the path override when set
+    pub target: Option<GatewayTarget>,
+    /// Pool of the address the target is bound to, replaced when it is rebound
+    target_upstreams: Mutex<Option<UpstreamPool>>,
 }
 impl HttpProxyConfig {
     pub fn new(server_addr: &str) -> Self {
+        Self::with_upstreams(UpstreamPool::single(server_addr))
+    }
+    pub fn with_upstreams(upstreams: UpstreamPool) -> Self {
         Self {
             overide_headers: HashMap::new(),
             path_override: PathOverride::Prefix("/".to_string()),
-            server_addr: server_addr.to_string(),
-            connection_count: 0,
+            upstreams,
+            connection_count: AtomicUsize::new(0),
+            request_hooks: Vec::new(),
+            timeouts: UpstreamTimeouts::default(),
+            traffic_limits: TrafficLimits::default(),
+            target: None,
+            target_upstreams: Mutex::new(None),
         }
     }
+    pub fn with_target(mut self, target: GatewayTarget) -> Self {
+        self.target = Some(target);
+        self
+    }
+    pub fn with_timeouts(mut self, timeouts: UpstreamTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+    pub fn with_traffic_limits(mut self, traffic_limits: TrafficLimits) -> Self {
+        self.traffic_limits = traffic_limits;
+        self
+    }
     pub fn with_path_override(mut self, path_override: PathOverride) -> Self {
         self.path_override = path_override;
         self
@@ -53,9 +130,22 @@ impl HttpProxyConfig {
             .insert(key.to_string(), value.to_string());
         self
     }
-    pub async fn modify_request(&self, mut request: Request) -> Result<Request, Error> {
-        request.path = match (&self.path_override, request.path.as_str()) {
+    pub fn with_request_hook(mut self, hook: impl RequestHook + 'static) -> Self {
+        self.request_hooks.push(Arc::new(hook));
+        self
+    }
+    pub async fn modify_request(
+        &self,
+        mut request: Request,
+        context: &ConnectionContext,
+    ) -> Result<Request, Error> {
+        let target = self.target.as_ref().and_then(GatewayTarget::get);
+        let path_override = target
+            .as_ref()
+            .map_or(&self.path_override, |(_, path_override)| path_override);
+        request.path = match (path_override, request.path.as_str()) {
             (PathOverride::Replace(path), _)
+            | (PathOverride::ReplaceRoot(path), "/")
             | (PathOverride::Prefix(path), "/")
             | (PathOverride::Append(path), "/") => path.to_string(),
             (PathOverride::Prefix(prefix), path) => {
@@ -64,22 +154,182 @@ impl HttpProxyConfig {
             (PathOverride::Append(suffix), path) => {
                 format!("{}{}", path, suffix)
             }
+            (PathOverride::ReplaceRoot(_), path) => path.to_string(),
         };
-        request
-            .headers
-            .retain(|(key, _)| !self.overide_headers.contains_key(key));
-        request
-            .headers
-            .extend(self.overide_headers.clone().into_iter());
+        request.strip_hop_by_hop_headers();
+        request.headers.retain(|(key, _)| {
+            !self
+                .overide_headers
+                .keys()
+                .any(|override_key| override_key.eq_ignore_ascii_case(key))
+        });
+        request.headers.extend(self.overide_headers.clone());
+        if let Some((server_addr, _)) = &target {
+            request.set_header("Host", server_addr);
+        }
+        for hook in &self.request_hooks {
+            hook.apply(&mut request, context);
+        }
         Ok(request)
     }
+    pub async fn connect(
+        &self,
+        request: &Request,
+    ) -> Result<(tokio::net::TcpStream, UpstreamLease), Error> {
+        match self.target.as_ref().and_then(GatewayTarget::get) {
+            Some((server_addr, _)) => {
+                self.target_upstreams(&server_addr)?
+                    .connect(Some(request), self.timeouts.connect)
+                    .await
+            }
+            None => {
+                self.upstreams
+                    .connect(Some(request), self.timeouts.connect)
+                    .await
+            }
+        }
+    }
+    /// Pool of the address the target is bound to, kept across connections so its health
+    /// marking persists
+    fn target_upstreams(&self, server_addr: &str) -> Result<UpstreamPool, Error> {
+        let mut target_upstreams = self
+            .target_upstreams
+            .lock()
+            .map_err(|_| Error::IoError("Upstreams lock poisoned"))?;
+        match target_upstreams.as_ref() {
+            Some(upstreams) if upstreams.addrs().eq([server_addr]) => Ok(upstreams.clone()),
+            _ => Ok(target_upstreams
+                .insert(UpstreamPool::single(server_addr))
+                .clone()),
+        }
+    }
+    /// Sends the request and waits for the response head within the first byte and handshake
+    /// timeouts. Returns None without sending anything if neither is set, or if the request has
+    /// a body which has to be forwarded before the server answers
+    pub async fn handshake(
+        &self,
+        request: &Request,
+        server: &mut tokio::net::TcpStream,
+    ) -> Result<Option<Response>, Error> {
+        if (self.timeouts.first_byte.is_none() && self.timeouts.handshake.is_none())
+            || request.has_body()
+        {
+            return Ok(None);
+        }
+        let started = tokio::time::Instant::now();
+        request.write_to_stream(server).await?;
+        if let Some(first_byte) = self.timeouts.first_byte {
+            let mut byte = [0u8; 1];
+            tokio::time::timeout(first_byte, server.peek(&mut byte))
+                .await
+                .map_err(|_| Error::Timeout("Server did not respond in time"))?
+                .map_err(|_| Error::IoError("Failed to read"))?;
+        }
+        let mut data = String::with_capacity(1024);
+        let read_head = read_until_empty_line(server, &mut data);
+        match self.timeouts.handshake {
+            Some(handshake) => {
+                tokio::time::timeout(handshake.saturating_sub(started.elapsed()), read_head)
+                    .await
+                    .map_err(|_| {
+                        Error::Timeout("Server did not complete the handshake in time")
+                    })??
+            }
+            None => read_head.await?,
+        }
+        Response::new(&data).map(Some)
+    }
+}
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Time given to `on_close` of force closed connections
+const FORCE_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Listener side options of a http gateway, independent of where connections are proxied to
+#[derive(Clone, Default)]
+pub struct GatewayOptions {
+    pub tls_acceptor: Option<TlsAcceptor>,
+    /// When set, running connections are given this long to finish after the gateway stops
+    /// accepting, before they are closed
+    pub drain_period: Option<Duration>,
+    /// Every connection starts with a PROXY protocol header carrying the real client address
+    pub proxy_protocol: bool,
+    pub access_log: Option<AccessLog>,
+}
+impl GatewayOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Terminates TLS on the listener, clients then connect with `https://` or `wss://`
+    pub fn with_tls(mut self, tls_acceptor: TlsAcceptor) -> Self {
+        self.tls_acceptor = Some(tls_acceptor);
+        self
+    }
+    pub fn with_drain_period(mut self, drain_period: Duration) -> Self {
+        self.drain_period = Some(drain_period);
+        self
+    }
+    /// For listeners behind a load balancer sending PROXY protocol v1 or v2 headers,
+    /// connections without one are rejected
+    pub fn with_proxy_protocol(mut self) -> Self {
+        self.proxy_protocol = true;
+        self
+    }
+    /// Writes a JSON line per connection to the access log
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
 }
+
+async fn wait_for_connections(connections: &Connections, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while connections.active_connections() > 0 {
+        if tokio::time::Instant::now() >= deadline {
+            return false;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    true
+}
+
+/// Waits for running connections to finish, then closes the remaining ones
+async fn drain_connections(
+    connections: &Connections,
+    drain_period: Duration,
+    force_close: &CancellationToken,
+) {
+    info!(
+        "Draining {} connections for up to {:?}",
+        connections.active_connections(),
+        drain_period
+    );
+    if wait_for_connections(connections, drain_period).await {
+        info!("All connections drained");
+        return;
+    }
+    warn!(
+        "Closing {} connections still running after drain period",
+        connections.active_connections()
+    );
+    force_close.cancel();
+    if !wait_for_connections(connections, FORCE_CLOSE_TIMEOUT).await {
+        error!("Connections did not close in time");
+    }
+}
+
 pub struct ServerConnectionManager {
     connection_id: usize,
+    upstream: UpstreamLease,
 }
 impl ServerConnectionManagerTrait for ServerConnectionManager {
     async fn on_open(&mut self) -> Result<(), Error> {
-        info!("#{} Server connection opened", self.connection_id);
+        info!(
+            "#{} Server connection opened to {}",
+            self.connection_id,
+            self.upstream.addr()
+        );
         Ok(())
     }
 
@@ -94,29 +344,49 @@ impl ServerConnectionManagerTrait for ServerConnectionManager {
 
 impl HttpProxyConfigTrait<ServerConnectionManager> for HttpProxyConfig {
     async fn new_connection(
-        &mut self,
+        &self,
         request: Request,
+        context: &ConnectionContext,
     ) -> Result<HttpProxyInstance<ServerConnectionManager>, Error> {
-        let request = self.modify_request(request).await?;
-        let server = tokio::net::TcpStream::connect(&self.server_addr)
-            .await
-            .map_err(|_| Error::IoError("Failed to connect to server"))?;
-        self.connection_count += 1;
+        let (mut server, upstream) = self.connect(&request).await?;
+        let request = self.modify_request(request, context).await?;
+        let response = self.handshake(&request, &mut server).await?;
+        let connection_id = self.connection_count.fetch_add(1, Ordering::Relaxed) + 1;
         Ok(HttpProxyInstance {
             request,
             server,
             manager: ServerConnectionManager {
-                connection_id: self.connection_count,
+                connection_id,
+                upstream,
             },
+            response_headers: Vec::new(),
+            instance_id: None,
+            response,
+            traffic_limits: self.traffic_limits.clone(),
+            recorder: None,
         })
     }
 }
 
-
 pub async fn start_simple_gateway_with_full_address(
     server_addr: String,
     listen_addr: SocketAddr,
     cancellation_token: &CancellationToken,
+) -> anyhow::Result<()> {
+    start_simple_gateway_with_upstreams(
+        UpstreamPool::single(&server_addr),
+        listen_addr,
+        cancellation_token,
+    )
+    .await
+}
+
+/// Forwards raw TCP connections to a pool of upstreams, consistent hashing falls back to
+/// round-robin as there is no request to hash
+pub async fn start_simple_gateway_with_upstreams(
+    upstreams: UpstreamPool,
+    listen_addr: SocketAddr,
+    cancellation_token: &CancellationToken,
 ) -> anyhow::Result<()> {
     let listener = tokio::net::TcpListener::bind(&listen_addr)
         .await
@@ -131,7 +401,7 @@ pub async fn start_simple_gateway_with_full_address(
                 }
                 c = listener.accept() => {c}
             } {
-                if let Err(e) = start_proxy_connection(&server_addr, client).await {
+                if let Err(e) = start_proxy_connection(&upstreams, client).await {
                     warn!("Failed to start proxy connection: {:?}", e);
                 }
             } else {
@@ -155,14 +425,12 @@ pub async fn start_simple_gateway(
 }
 
 async fn start_proxy_connection(
-    server_addr: &str,
+    upstreams: &UpstreamPool,
     mut client: tokio::net::TcpStream,
 ) -> Result<(), Error> {
-    let server_addr = server_addr.to_string();
+    let upstreams = upstreams.clone();
     tokio::spawn(async move {
-        let mut server = tokio::net::TcpStream::connect(server_addr)
-            .await
-            .map_err(|_| Error::IoError("Failed to connect to server"))?;
+        let (mut server, _upstream) = upstreams.connect(None, None).await?;
         let proxy_result = async {
             tokio::io::copy_bidirectional(&mut client, &mut server)
                 .await
@@ -195,32 +463,138 @@ pub async fn start_simple_http_gateway_with_proxy_config<
     T: ServerConnectionManagerTrait + 'static + Send + Sync,
     P: HttpProxyConfigTrait<T> + Send + Sync + 'static,
 >(
-    mut proxy_config: P,
+    proxy_config: P,
     listen_addr: SocketAddr,
     cancellation_token: &CancellationToken,
 ) -> anyhow::Result<()> {
+    start_http_gateway_with_options(
+        proxy_config,
+        listen_addr,
+        GatewayOptions::new(),
+        cancellation_token,
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Reads the PROXY header and terminates TLS if configured, then starts proxying. Runs on the
+/// task of the connection, so a slow client only holds up itself
+async fn accept_connection<
+    T: ServerConnectionManagerTrait + 'static + Send + Sync,
+    P: HttpProxyConfigTrait<T> + Send + Sync + 'static,
+>(
+    proxy_config: &P,
+    mut client: tokio::net::TcpStream,
+    peer_addr: SocketAddr,
+    options: &GatewayOptions,
+    connections: &Connections,
+    active_connection: ActiveConnection,
+    force_close: &CancellationToken,
+) -> Result<(), Error> {
+    let mut context = ConnectionContext { peer_addr };
+    if options.proxy_protocol {
+        match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut client)).await {
+            Ok(Ok(Some(client_addr))) => context.peer_addr = client_addr,
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(Error::IoError("PROXY header timed out")),
+        }
+    }
+    match &options.tls_acceptor {
+        Some(tls_acceptor) => {
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, tls_acceptor.accept(client)).await {
+                Ok(Ok(client)) => {
+                    start_http_proxy_connection(
+                        proxy_config,
+                        client,
+                        &context,
+                        connections,
+                        active_connection,
+                        force_close,
+                        options.access_log.as_ref(),
+                    )
+                    .await
+                }
+                Ok(Err(_)) => Err(Error::IoError("TLS handshake failed")),
+                Err(_) => Err(Error::IoError("TLS handshake timed out")),
+            }
+        }
+        None => {
+            start_http_proxy_connection(
+                proxy_config,
+                client,
+                &context,
+                connections,
+                active_connection,
+                force_close,
+                options.access_log.as_ref(),
+            )
+            .await
+        }
+    }
+}
+
+/// Starts the gateway, the returned handle completes once the gateway stopped and,
+/// with a drain period, its connections are closed
+pub async fn start_http_gateway_with_options<
+    T: ServerConnectionManagerTrait + 'static + Send + Sync,
+    P: HttpProxyConfigTrait<T> + Send + Sync + 'static,
+>(
+    proxy_config: P,
+    listen_addr: SocketAddr,
+    options: GatewayOptions,
+    cancellation_token: &CancellationToken,
+) -> anyhow::Result<JoinHandle<()>> {
     let listener = tokio::net::TcpListener::bind(&listen_addr)
         .await
         .map_err(|_| anyhow::anyhow!("Failed to start simple gateway: Failed to bind address"))?;
+    let proxy_config = Arc::new(proxy_config);
     let cancellation_token = cancellation_token.clone();
-    tokio::spawn(async move {
+    let connections = Connections::new();
+    let force_close = CancellationToken::new();
+    Ok(tokio::spawn(async move {
         loop {
-            if let Ok((client, _)) = tokio::select! {
+            if let Ok((client, peer_addr)) = tokio::select! {
                 _ = cancellation_token.cancelled() => {
                     error!("Cancellation token cancelled");
                     break;
                 }
                 c = listener.accept() => {c}
             } {
-                if let Err(e) = start_http_proxy_connection(&mut proxy_config, client).await {
-                    warn!("Failed to start proxy connection: {:?}", e);
-                }
+                // Counted from the start, so draining waits for connections still being set up
+                let active_connection = connections.new_connection();
+                let proxy_config = proxy_config.clone();
+                let options = options.clone();
+                let connections = connections.clone();
+                let force_close = force_close.clone();
+                tokio::spawn(async move {
+                    let accepted = accept_connection(
+                        proxy_config.as_ref(),
+                        client,
+                        peer_addr,
+                        &options,
+                        &connections,
+                        active_connection,
+                        &force_close,
+                    );
+                    tokio::select! {
+                        result = accepted => {
+                            if let Err(e) = result {
+                                warn!("Failed to start proxy connection: {:?}", e);
+                            }
+                        }
+                        _ = force_close.cancelled() => {}
+                    }
+                });
             } else {
                 error!("Listener closed");
                 break;
             }
         }
         cancellation_token.cancel();
-    });
-    Ok(())
-}
\ No newline at end of file
+        drop(listener);
+        if let Some(drain_period) = options.drain_period {
+            drain_connections(&connections, drain_period, &force_close).await;
+        }
+    }))
+}
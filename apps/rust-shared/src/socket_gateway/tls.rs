@@ -0,0 +1,27 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Builds a TLS acceptor for the gateway listeners from PEM encoded certificate chain and key
+/// Only HTTP/1.1 is advertised over ALPN, as websocket upgrades are not supported over HTTP/2
+pub fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> anyhow::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .context("Failed to read cert file")?
+        .collect::<Result<Vec<_>, _>>()
+        .context("Failed to parse cert file")?;
+    let key = PrivateKeyDer::from_pem_file(key_path).context("Failed to read key file")?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
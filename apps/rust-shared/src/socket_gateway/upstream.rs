@@ -0,0 +1,310 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::socket_gateway::http_proxy::{Error, Request};
+
+/// Time an upstream is skipped after a failed connect
+const DEFAULT_UNHEALTHY_PERIOD: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum HashKey {
+    /// Value of a request header, requests without it are balanced round-robin
+    Header(String),
+    /// Request path, including the query
+    Path,
+}
+
+#[derive(Debug, Clone)]
+pub enum BalanceStrategy {
+    RoundRobin,
+    /// Upstream with the fewest running connections of this pool
+    LeastConnections,
+    /// Same key goes to the same upstream while it is healthy, rendezvous hashing is used
+    /// so only the keys of a failed upstream move
+    ConsistentHash(HashKey),
+}
+
+/// Parses `round-robin`, `least-connections`, `hash-path` or `hash-header:<name>`
+impl std::str::FromStr for BalanceStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "round-robin" => Ok(BalanceStrategy::RoundRobin),
+            "least-connections" => Ok(BalanceStrategy::LeastConnections),
+            "hash-path" => Ok(BalanceStrategy::ConsistentHash(HashKey::Path)),
+            strategy => match strategy.strip_prefix("hash-header:") {
+                Some(name) if !name.is_empty() => Ok(BalanceStrategy::ConsistentHash(
+                    HashKey::Header(name.to_string()),
+                )),
+                _ => anyhow::bail!("Unknown balance strategy: {}", strategy),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Upstream {
+    addr: String,
+    active_connections: AtomicUsize,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn is_healthy(&self) -> bool {
+        self.unhealthy_until
+            .lock()
+            .map(|until| until.is_none_or(|until| Instant::now() >= until))
+            .unwrap_or(true)
+    }
+}
+
+/// Upstream servers a gateway forwards to.
+/// Upstreams failing to connect are passively marked unhealthy and skipped for a while,
+/// a connection is retried on the next upstream before giving up
+#[derive(Debug, Clone)]
+pub struct UpstreamPool {
+    upstreams: Arc<Vec<Upstream>>,
+    strategy: BalanceStrategy,
+    next: Arc<AtomicUsize>,
+    unhealthy_period: Duration,
+    max_attempts: usize,
+}
+
+/// Counts as a running connection of its upstream until dropped
+#[derive(Debug)]
+pub struct UpstreamLease {
+    pool: UpstreamPool,
+    index: usize,
+}
+
+impl Drop for UpstreamLease {
+    fn drop(&mut self) {
+        if let Some(upstream) = self.pool.upstreams.get(self.index) {
+            upstream.active_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl UpstreamLease {
+    pub fn addr(&self) -> &str {
+        self.pool
+            .upstreams
+            .get(self.index)
+            .map_or("", |upstream| upstream.addr.as_str())
+    }
+}
+
+impl UpstreamPool {
+    pub fn new(addrs: Vec<String>, strategy: BalanceStrategy) -> Self {
+        let max_attempts = addrs.len();
+        Self {
+            upstreams: Arc::new(
+                addrs
+                    .into_iter()
+                    .map(|addr| Upstream {
+                        addr,
+                        active_connections: AtomicUsize::new(0),
+                        unhealthy_until: Mutex::new(None),
+                    })
+                    .collect(),
+            ),
+            strategy,
+            next: Arc::new(AtomicUsize::new(0)),
+            unhealthy_period: DEFAULT_UNHEALTHY_PERIOD,
+            max_attempts,
+        }
+    }
+    pub fn single(addr: &str) -> Self {
+        Self::new(vec![addr.to_string()], BalanceStrategy::RoundRobin)
+    }
+    pub fn with_unhealthy_period(mut self, unhealthy_period: Duration) -> Self {
+        self.unhealthy_period = unhealthy_period;
+        self
+    }
+    /// Upstreams tried per connection, defaults to all of them
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = &str> {
+        self.upstreams.iter().map(|upstream| upstream.addr.as_str())
+    }
+
+    fn round_robin_order(&self) -> Vec<usize> {
+        let len = self.upstreams.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len).map(|i| (start + i) % len).collect()
+    }
+
+    /// Upstreams to try for the request, in order. Healthy upstreams come first
+    fn candidates(&self, request: Option<&Request>) -> Vec<usize> {
+        let mut order = match &self.strategy {
+            BalanceStrategy::RoundRobin => self.round_robin_order(),
+            BalanceStrategy::LeastConnections => {
+                let mut order = self.round_robin_order();
+                // Stable sort, ties keep their round-robin order
+                order
+                    .sort_by_key(|&i| self.upstreams[i].active_connections.load(Ordering::Relaxed));
+                order
+            }
+            BalanceStrategy::ConsistentHash(hash_key) => {
+                let key = request.and_then(|request| match hash_key {
+                    HashKey::Header(name) => request.header(name),
+                    HashKey::Path => Some(request.path.as_str()),
+                });
+                match key {
+                    Some(key) => {
+                        let mut order = (0..self.upstreams.len()).collect::<Vec<_>>();
+                        order.sort_by_cached_key(|&i| {
+                            let mut hasher = DefaultHasher::new();
+                            (key, &self.upstreams[i].addr).hash(&mut hasher);
+                            std::cmp::Reverse(hasher.finish())
+                        });
+                        order
+                    }
+                    None => self.round_robin_order(),
+                }
+            }
+        };
+        order.sort_by_key(|&i| !self.upstreams[i].is_healthy());
+        order.truncate(self.max_attempts);
+        order
+    }
+
+    fn set_unhealthy(&self, index: usize, unhealthy: bool) {
+        if let Ok(mut until) = self.upstreams[index].unhealthy_until.lock() {
+            *until = unhealthy.then(|| Instant::now() + self.unhealthy_period);
+        }
+    }
+
+    /// Connects to an upstream chosen for the request, retrying on the next ones on failure.
+    /// An upstream not accepting within `connect_timeout` counts as failed
+    pub async fn connect(
+        &self,
+        request: Option<&Request>,
+        connect_timeout: Option<Duration>,
+    ) -> Result<(TcpStream, UpstreamLease), Error> {
+        let mut timed_out = false;
+        for index in self.candidates(request) {
+            let addr = &self.upstreams[index].addr;
+            let result = match connect_timeout {
+                Some(connect_timeout) => {
+                    tokio::time::timeout(connect_timeout, TcpStream::connect(addr))
+                        .await
+                        .unwrap_or_else(|_| Err(std::io::Error::from(std::io::ErrorKind::TimedOut)))
+                }
+                None => TcpStream::connect(addr).await,
+            };
+            match result {
+                Ok(stream) => {
+                    self.set_unhealthy(index, false);
+                    self.upstreams[index]
+                        .active_connections
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Ok((
+                        stream,
+                        UpstreamLease {
+                            pool: self.clone(),
+                            index,
+                        },
+                    ));
+                }
+                Err(e) => {
+                    warn!("Failed to connect to upstream {}: {:?}", addr, e);
+                    timed_out |= e.kind() == std::io::ErrorKind::TimedOut;
+                    self.set_unhealthy(index, true);
+                }
+            }
+        }
+        if timed_out {
+            return Err(Error::Timeout("Timed out connecting to server"));
+        }
+        Err(Error::IoError("Failed to connect to server"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: BalanceStrategy) -> UpstreamPool {
+        UpstreamPool::new(
+            vec!["a:1".to_string(), "b:1".to_string(), "c:1".to_string()],
+            strategy,
+        )
+    }
+
+    #[test]
+    fn test_round_robin_skips_unhealthy() {
+        let pool = pool(BalanceStrategy::RoundRobin);
+        assert_eq!(pool.candidates(None), vec![0, 1, 2]);
+        assert_eq!(pool.candidates(None), vec![1, 2, 0]);
+        pool.set_unhealthy(2, true);
+        assert_eq!(pool.candidates(None), vec![0, 1, 2]);
+        assert_eq!(pool.candidates(None), vec![0, 1, 2]);
+        let pool = pool.with_max_attempts(2);
+        assert_eq!(pool.candidates(None).len(), 2);
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let pool = pool(BalanceStrategy::LeastConnections);
+        pool.upstreams[0]
+            .active_connections
+            .store(2, Ordering::Relaxed);
+        pool.upstreams[1]
+            .active_connections
+            .store(1, Ordering::Relaxed);
+        assert_eq!(pool.candidates(None)[0], 2);
+        let lease = UpstreamLease {
+            pool: pool.clone(),
+            index: 0,
+        };
+        drop(lease);
+        assert_eq!(
+            pool.upstreams[0].active_connections.load(Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_consistent_hash() {
+        let pool = pool(BalanceStrategy::ConsistentHash(HashKey::Header(
+            "X-Session".to_string(),
+        )));
+        let request = Request::new("GET / HTTP/1.1\r\nX-Session: abc\r\n\r\n").unwrap();
+        let first = pool.candidates(Some(&request));
+        assert_eq!(pool.candidates(Some(&request)), first);
+        pool.set_unhealthy(first[0], true);
+        let failover = pool.candidates(Some(&request));
+        assert_eq!(failover[0], first[1]);
+        assert_eq!(failover[2], first[0]);
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert!(matches!(
+            "least-connections".parse(),
+            Ok(BalanceStrategy::LeastConnections)
+        ));
+        assert!(matches!(
+            "hash-header:X-Session".parse(),
+            Ok(BalanceStrategy::ConsistentHash(HashKey::Header(name))) if name == "X-Session"
+        ));
+        assert!(matches!(
+            "hash-path".parse(),
+            Ok(BalanceStrategy::ConsistentHash(HashKey::Path))
+        ));
+        assert!("hash-header:".parse::<BalanceStrategy>().is_err());
+        assert!("random".parse::<BalanceStrategy>().is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::socket_gateway::http_proxy::{Error, Response, read_until_empty_line};
+
+pub const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+pub const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+/// Frames larger than this are rejected instead of being buffered
+const MAX_FRAME_LENGTH: u64 = 256 * 1024 * 1024;
+/// Fragmented messages are rejected once their fragments add up to more than this
+const MAX_MESSAGE_LENGTH: usize = 256 * 1024 * 1024;
+/// Nonce of the RFC 6455 example, servers only check that a key is sent
+const CLIENT_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+/// A single RFC 6455 frame, with its payload unmasked
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn is_control(&self) -> bool {
+        self.opcode & 0x8 != 0
+    }
+
+    /// Parses the frame at the start of `data`, returns it with the number of bytes it took,
+    /// or None while the frame is incomplete
+    pub fn parse(data: &[u8]) -> Result<Option<(Frame, usize)>, Error> {
+        let [first, second, rest @ ..] = data else {
+            return Ok(None);
+        };
+        let masked = second & 0x80 != 0;
+        let (length, length_size) = match second & 0x7F {
+            126 => match rest {
+                [a, b, ..] => (u16::from_be_bytes([*a, *b]) as u64, 2),
+                _ => return Ok(None),
+            },
+            127 => match rest.get(..8) {
+                Some(bytes) => {
+                    let mut length = [0u8; 8];
+                    length.copy_from_slice(bytes);
+                    (u64::from_be_bytes(length), 8)
+                }
+                None => return Ok(None),
+            },
+            length => (length as u64, 0),
+        };
+        if length > MAX_FRAME_LENGTH {
+            return Err(Error::TrafficCapExceeded("Websocket frame too large"));
+        }
+        let header_length = 2 + length_size + if masked { 4 } else { 0 };
+        let frame_length = header_length + length as usize;
+        if data.len() < frame_length {
+            return Ok(None);
+        }
+        let mut payload = data[header_length..frame_length].to_vec();
+        if masked {
+            let mask = &data[header_length - 4..header_length];
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        Ok(Some((
+            Frame {
+                fin: first & 0x80 != 0,
+                opcode: first & 0x0F,
+                payload,
+            },
+            frame_length,
+        )))
+    }
+
+    /// Encodes the frame, clients must send their frames masked
+    pub fn encode(&self, mask: Option<[u8; 4]>) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.payload.len() + 14);
+        data.push(if self.fin { 0x80 } else { 0 } | self.opcode);
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match self.payload.len() {
+            length @ 0..=125 => data.push(mask_bit | length as u8),
+            length @ 126..=0xFFFF => {
+                data.push(mask_bit | 126);
+                data.extend((length as u16).to_be_bytes());
+            }
+            length => {
+                data.push(mask_bit | 127);
+                data.extend((length as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                data.extend(mask);
+                data.extend(
+                    self.payload
+                        .iter()
+                        .enumerate()
+                        .map(|(i, byte)| byte ^ mask[i % 4]),
+                );
+            }
+            None => data.extend(&self.payload),
+        }
+        data
+    }
+}
+
+/// Reassembles the messages of one direction of a websocket from its byte stream
+#[derive(Debug, Default)]
+pub struct MessageReader {
+    buffer: Vec<u8>,
+    /// Opcode and payload of a fragmented message
+    fragments: Option<(u8, Vec<u8>)>,
+}
+
+impl MessageReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds data read from the stream, returns the messages it completed as opcode and
+    /// payload. Control frames interleaved in fragmented messages are returned on their own.
+    /// Frames and messages over their maximum length fail with `TrafficCapExceeded`
+    pub fn feed(&mut self, data: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, Error> {
+        self.buffer.extend_from_slice(data);
+        let mut messages = Vec::new();
+        let mut consumed = 0;
+        while let Some((frame, length)) = Frame::parse(&self.buffer[consumed..])? {
+            consumed += length;
+            if frame.is_control() {
+                messages.push((frame.opcode, frame.payload));
+                continue;
+            }
+            let (opcode, mut payload) = match (self.fragments.take(), frame.opcode) {
+                (Some(fragments), OPCODE_CONTINUATION) => fragments,
+                (None, OPCODE_CONTINUATION) => {
+                    return Err(Error::ParseError("Unexpected continuation frame"));
+                }
+                (_, opcode) => (opcode, Vec::new()),
+            };
+            if payload.len() + frame.payload.len() > MAX_MESSAGE_LENGTH {
+                return Err(Error::TrafficCapExceeded("Websocket message too large"));
+            }
+            payload.extend(frame.payload);
+            if frame.fin {
+                messages.push((opcode, payload));
+            } else {
+                self.fragments = Some((opcode, payload));
+            }
+        }
+        self.buffer.drain(..consumed);
+        Ok(messages)
+    }
+}
+
+/// Opens a websocket to `path` on the server at `addr` as a client
+pub async fn connect(addr: &str, path: &str) -> Result<TcpStream, Error> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .map_err(|_| Error::IoError("Failed to connect"))?;
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {addr}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {CLIENT_KEY}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|_| Error::IoError("Failed to send"))?;
+    let mut data = String::with_capacity(1024);
+    read_until_empty_line(&mut stream, &mut data).await?;
+    if Response::new(&data)?.status_code() != Some(101) {
+        return Err(Error::IoError("Server refused the websocket upgrade"));
+    }
+    Ok(stream)
+}
+
+/// Writes a message as a single frame, masked as clients must
+pub async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin),
+    opcode: u8,
+    payload: Vec<u8>,
+) -> Result<(), Error> {
+    let random = uuid::Uuid::new_v4().into_bytes();
+    let frame = Frame {
+        fin: true,
+        opcode,
+        payload,
+    };
+    stream
+        .write_all(&frame.encode(Some([random[0], random[1], random[2], random[3]])))
+        .await
+        .map_err(|_| Error::IoError("Failed to send"))
+}
+
+/// Reads the messages a server sends on a websocket
+pub struct WebSocketReader<R> {
+    stream: R,
+    reader: MessageReader,
+    messages: VecDeque<(u8, Vec<u8>)>,
+}
+
+impl<R: AsyncRead + Unpin> WebSocketReader<R> {
+    pub fn new(stream: R) -> Self {
+        Self {
+            stream,
+            reader: MessageReader::new(),
+            messages: VecDeque::new(),
+        }
+    }
+
+    /// Next message as opcode and payload, None once the server closed the connection
+    pub async fn next(&mut self) -> Result<Option<(u8, Vec<u8>)>, Error> {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            if let Some(message) = self.messages.pop_front() {
+                return Ok(Some(message));
+            }
+            let read = self
+                .stream
+                .read(&mut buf)
+                .await
+                .map_err(|_| Error::IoError("Failed to read"))?;
+            if read == 0 {
+                return Ok(None);
+            }
+            self.messages.extend(self.reader.feed(&buf[..read])?);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_frames() {
+        let frame = Frame {
+            fin: true,
+            opcode: OPCODE_TEXT,
+            payload: b"hello".to_vec(),
+        };
+        let masked = frame.encode(Some([1, 2, 3, 4]));
+        assert_eq!(Frame::parse(&masked).unwrap(), Some((frame.clone(), 11)));
+        assert_eq!(Frame::parse(&masked[..8]).unwrap(), None);
+
+        let large = Frame {
+            fin: true,
+            opcode: OPCODE_BINARY,
+            payload: vec![7; 70_000],
+        };
+        let encoded = large.encode(None);
+        assert_eq!(encoded.len(), 70_010);
+        assert_eq!(Frame::parse(&encoded).unwrap(), Some((large, 70_010)));
+    }
+
+    #[test]
+    #[allow(clippy::unwrap_used)]
+    fn test_message_reader() {
+        let mut data = Frame {
+            fin: false,
+            opcode: OPCODE_TEXT,
+            payload: b"hel".to_vec(),
+        }
+        .encode(None);
+        data.extend(
+            Frame {
+                fin: true,
+                opcode: 0x9,
+                payload: Vec::new(),
+            }
+            .encode(None),
+        );
+        data.extend(
+            Frame {
+                fin: true,
+                opcode: OPCODE_CONTINUATION,
+                payload: b"lo".to_vec(),
+            }
+            .encode(None),
+        );
+        let mut reader = MessageReader::new();
+        assert_eq!(reader.feed(&data[..4]).unwrap(), vec![]);
+        assert_eq!(
+            reader.feed(&data[4..]).unwrap(),
+            vec![(0x9, Vec::new()), (OPCODE_TEXT, b"hello".to_vec())]
+        );
+    }
+}
@@ -1,16 +1,22 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
 use std::time::Duration;
 
+use tokio::sync::watch;
 use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 use tonic::service::interceptor::InterceptedService;
-use tonic::{Request, Status, transport::Channel};
-use tracing::{error, info};
+use tonic::{Code, Request, Status, transport::Channel};
+use tracing::{error, info, warn};
 
-use crate::add_version;
+use crate::instance_manager::info_service_client::InfoServiceClient;
 use crate::instance_manager::try_service_client::TryServiceClient;
 use crate::instance_manager::{
-    HealthCheck, InstanceDescription, InstanceId, InstanceType, Services,
+    HealthCheck, InstanceDescription, InstanceId, InstanceType, KillInstanceRequest, KillReason,
+    Labels, ReleaseRequest, ServerInfoRequest, Services,
 };
+use crate::{PROTO_VERSION, add_version};
 
 const HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(1_000);
 const MAX_RETRIES: usize = 3;
@@ -18,29 +24,145 @@ const MAX_RETRIES: usize = 3;
 type Client =
     TryServiceClient<InterceptedService<Channel, fn(Request<()>) -> Result<Request<()>, Status>>>;
 
+pub type LivenessFuture<'a> = Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+/// Checks that an instance is able to do its work, beyond its process being alive
+pub trait LivenessCheck: Send + Sync {
+    fn check(&self) -> LivenessFuture<'_>;
+}
+
+/// Gates the heartbeat: once the check failed `max_failures` times in a row, heartbeats stop
+/// and the instance is reported as failing its health check, with the last error
+pub struct Liveness {
+    pub check: Box<dyn LivenessCheck>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub max_failures: usize,
+}
+
+/// Lets an instance that is alive but busy, e.g. restarting, keep parents from picking it. The
+/// reason goes out with every heartbeat, right away when it changes, and the liveness check is
+/// paused meanwhile. The heartbeat responses tell whether the parent released the instance
+#[derive(Clone)]
+pub struct Availability {
+    unavailable_reason: Arc<watch::Sender<Option<String>>>,
+    release_requested: Arc<watch::Sender<bool>>,
+}
+
+/// Keeps the instance unavailable until dropped, then restores the previous reason
+pub struct Unavailable {
+    availability: Availability,
+    previous: Option<String>,
+}
+
+impl Drop for Unavailable {
+    fn drop(&mut self) {
+        self.availability
+            .unavailable_reason
+            .send_replace(self.previous.take());
+    }
+}
+
+impl Availability {
+    fn new() -> Self {
+        Availability {
+            unavailable_reason: Arc::new(watch::channel(None).0),
+            release_requested: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn set_unavailable(&self, reason: &str) -> Unavailable {
+        Unavailable {
+            availability: self.clone(),
+            previous: self
+                .unavailable_reason
+                .send_replace(Some(reason.to_string())),
+        }
+    }
+
+    /// Changes whenever the parent starts or stops asking for the release of the instance
+    pub fn release_requests(&self) -> watch::Receiver<bool> {
+        self.release_requested.subscribe()
+    }
+}
+
+/// Runs the liveness check periodically, resolves with the last error once it failed too
+/// often in a row. Never resolves without a check. Paused while the instance is unavailable,
+/// its failures start over afterwards
+async fn watch_liveness(
+    liveness: Option<Liveness>,
+    mut unavailable_reason: watch::Receiver<Option<String>>,
+) -> String {
+    let Some(liveness) = liveness else {
+        return std::future::pending().await;
+    };
+    let mut failures = 0;
+    loop {
+        tokio::time::sleep(liveness.interval).await;
+        if unavailable_reason.has_changed().unwrap_or(false) {
+            failures = 0;
+        }
+        if unavailable_reason.borrow_and_update().is_some() {
+            continue;
+        }
+        let checked = tokio::time::timeout(liveness.timeout, liveness.check.check()).await;
+        // The instance started or finished restarting during the check, its result is stale
+        if unavailable_reason.has_changed().unwrap_or(false) {
+            failures = 0;
+            continue;
+        }
+        let error = match checked {
+            Ok(Ok(())) => {
+                failures = 0;
+                continue;
+            }
+            Ok(Err(e)) => format!("{:#}", e),
+            Err(_) => "Liveness check timed out".to_string(),
+        };
+        failures += 1;
+        warn!(
+            "Liveness check failed {}/{}: {}",
+            failures, liveness.max_failures, error
+        );
+        if failures >= liveness.max_failures {
+            return error;
+        }
+    }
+}
+
 fn start_heart_beat(
     instance_id: &InstanceId,
     mut client: Client,
+    liveness: Option<Liveness>,
     cancellation_token: &CancellationToken,
-) {
+) -> Availability {
     let instance_id = instance_id.clone();
     let cancellation_token = cancellation_token.clone();
+    let availability = Availability::new();
+    let mut unavailable_reason = availability.unavailable_reason.subscribe();
+    let liveness = watch_liveness(liveness, availability.unavailable_reason.subscribe());
+    let release_requested = availability.release_requested.clone();
     tokio::spawn(async move {
+        tokio::pin!(liveness);
         let mut next_heart_beat = Instant::now();
         let mut retries = 0;
         loop {
             let request = Request::new(InstanceDescription {
                 instance_id: Some(instance_id.clone()),
-                health_check: Some(HealthCheck { timestamp_ms: None }),
+                health_check: Some(HealthCheck {
+                    timestamp_ms: None,
+                    unavailable_reason: unavailable_reason.borrow_and_update().clone(),
+                }),
                 ..Default::default()
             });
-            let response = client
-                .try_update_instance_description(request)
-                .await
-                .map(|v| v.into_inner().value);
-            match (response, retries) {
+            let response = client.try_heartbeat(request).await.map(|v| v.into_inner());
+            match (response.as_ref().map(|v| v.value), retries) {
                 (Ok(true), _) => {
                     retries = 0;
+                    let requested = response.is_ok_and(|v| v.release_requested);
+                    release_requested.send_if_modified(|release_requested| {
+                        std::mem::replace(release_requested, requested) != requested
+                    });
                 }
                 (Ok(false), _) | (_, MAX_RETRIES) => {
                     error!("Instance is unhealthy should be killed");
@@ -59,6 +181,24 @@ fn start_heart_beat(
                     error!("Heartbeat cancelled");
                     break;
                 }
+                error = &mut liveness => {
+                    error!("Liveness check failed, stopping heartbeats: {}", error);
+                    if let Err(e) = send_kill_request(
+                        &mut client,
+                        &instance_id,
+                        KillReason::HealthCheckFailed,
+                        Some(error),
+                    )
+                    .await
+                    {
+                        error!("Failed to report the failed liveness check: {:?}", e);
+                    }
+                    break;
+                }
+                Ok(()) = unavailable_reason.changed() => {
+                    next_heart_beat = Instant::now();
+                    continue;
+                }
                 _ = tokio::time::sleep_until(next_heart_beat) => {
                     continue;
                 }
@@ -66,12 +206,14 @@ fn start_heart_beat(
         }
         cancellation_token.cancel();
     });
+    availability
 }
 
 pub async fn initialize_health_loop(
     instance_id: &InstanceId,
     instance_type: &InstanceType,
     services: &Option<Services>,
+    labels: &Option<Labels>,
     client: &mut Client,
 ) -> anyhow::Result<()> {
     match client
@@ -79,6 +221,7 @@ pub async fn initialize_health_loop(
             instance_id: Some(instance_id.clone()),
             instance_type: Some(*instance_type as i32),
             services: services.clone(),
+            labels: labels.clone(),
             ..Default::default()
         }))
         .await
@@ -96,23 +239,154 @@ pub async fn initialize_health_loop(
     }
     Ok(())
 }
+/// Fails if the instance manager does not accept the proto version of this build, instead of
+/// every later call failing with `Wrong protocol versions`
+pub async fn check_server_info(channel: &Channel) -> anyhow::Result<()> {
+    let mut client = InfoServiceClient::new(channel.clone());
+    let server_info = match client
+        .get_server_info(Request::new(ServerInfoRequest {
+            proto_version: PROTO_VERSION.to_string(),
+        }))
+        .await
+    {
+        Ok(server_info) => server_info.into_inner(),
+        Err(status) if status.code() == Code::Unimplemented => {
+            warn!("Instance manager predates GetServerInfo, skipping the version handshake");
+            return Ok(());
+        }
+        Err(status) => {
+            return Err(anyhow::anyhow!(
+                "Failed to get instance manager info: {}",
+                status
+            ));
+        }
+    };
+    anyhow::ensure!(
+        server_info.client_version_accepted,
+        "Proto version {} is not accepted by the instance manager (build {}), it accepts {:?}. \
+         Update this service to the instance manager's version",
+        PROTO_VERSION,
+        server_info.build_version,
+        server_info.accepted_proto_versions
+    );
+    info!(
+        "Instance manager build {} on proto version {}, features: {:?}",
+        server_info.build_version, server_info.proto_version, server_info.features
+    );
+    Ok(())
+}
+
+/// Registers the instance and starts its heartbeat, the returned handle marks it unavailable
 pub async fn start_health_loop(
     instance_id: &InstanceId,
     instance_type: &InstanceType,
     services: &Option<Services>,
+    labels: &Option<Labels>,
+    liveness: Option<Liveness>,
     channel: &Channel,
     cancellation_token: &CancellationToken,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<Availability> {
+    check_server_info(channel).await?;
     let mut client: Client = TryServiceClient::with_interceptor(channel.clone(), add_version);
-    if let Err(e) = initialize_health_loop(instance_id, instance_type, services, &mut client).await
+    if let Err(e) =
+        initialize_health_loop(instance_id, instance_type, services, labels, &mut client).await
     {
         error!("Failed to initialize health loop: {:?}", e);
         return Err(e);
     }
-    start_heart_beat(instance_id, client, cancellation_token);
+    Ok(start_heart_beat(
+        instance_id,
+        client,
+        liveness,
+        cancellation_token,
+    ))
+}
+
+async fn send_kill_request(
+    client: &mut Client,
+    instance_id: &InstanceId,
+    kill_reason: KillReason,
+    message: Option<String>,
+) -> anyhow::Result<()> {
+    let killed = client
+        .try_update_instance_description(Request::new(InstanceDescription {
+            instance_id: Some(instance_id.clone()),
+            kill_instance_request: Some(KillInstanceRequest {
+                kill_reason: kill_reason as i32,
+                timestamp_ms: None,
+                message,
+            }),
+            ..Default::default()
+        }))
+        .await
+        .map_err(|e| anyhow::anyhow!("Error killing instance: {}", e))?
+        .into_inner()
+        .value;
+    anyhow::ensure!(killed, "Instance was already killed");
+    Ok(())
+}
+
+/// Marks the instance as killed, e.g. to deregister it before shutting down
+pub async fn kill_instance(
+    instance_id: &InstanceId,
+    kill_reason: KillReason,
+    channel: &Channel,
+) -> anyhow::Result<()> {
+    let mut client: Client = TryServiceClient::with_interceptor(channel.clone(), add_version);
+    send_kill_request(&mut client, instance_id, kill_reason, None).await
+}
+
+/// Asks a child instance of `parent_id` to reset itself and go back to the pool instead of killing it
+pub async fn release_instance(
+    instance_id: &InstanceId,
+    parent_id: &InstanceId,
+    channel: &Channel,
+) -> anyhow::Result<()> {
+    let mut client: Client = TryServiceClient::with_interceptor(channel.clone(), add_version);
+    let released = client
+        .try_update_instance_description(Request::new(InstanceDescription {
+            instance_id: Some(instance_id.clone()),
+            release_request: Some(ReleaseRequest {
+                timestamp_ms: None,
+                requested_by: Some(parent_id.clone()),
+            }),
+            ..Default::default()
+        }))
+        .await
+        .map_err(|e| anyhow::anyhow!("Error releasing instance: {}", e))?
+        .into_inner()
+        .value;
+    anyhow::ensure!(released, "Instance is dead or not a child of the parent");
     Ok(())
 }
 
+/// Detaches a released instance from its parent, so it can be handed out again
+pub async fn detach_instance(instance_id: &InstanceId, channel: &Channel) -> anyhow::Result<()> {
+    let mut client: Client = TryServiceClient::with_interceptor(channel.clone(), add_version);
+    let detached = client
+        .try_detach_instance(Request::new(instance_id.clone()))
+        .await
+        .map_err(|e| anyhow::anyhow!("Error detaching instance: {}", e))?
+        .into_inner()
+        .value;
+    anyhow::ensure!(detached, "Instance is dead or was not released");
+    Ok(())
+}
+
+/// Listens for SIGTERM and SIGINT, the returned future resolves with the name of the first
+/// one received
+pub fn shutdown_signal() -> anyhow::Result<impl Future<Output = &'static str>> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    Ok(async move {
+        tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+        }
+    })
+}
+
 pub fn generate_instance_id(prefix: &str) -> String {
     format!("{}-{}", prefix, uuid::Uuid::new_v4())
 }